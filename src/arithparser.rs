@@ -1,126 +1,175 @@
 use crate::*;
+use crate::tokenizer::Token;
 
 
-pub fn parse(prog: &[&str], i: &mut usize) -> Vec<Command> {
-    let parsed = parse_expression(prog, i);
+type Parsed = Vec<(Command, SourceReference)>;
+type ParseResult = Result<Parsed, (String, Span)>;
 
-    *i -= 1;
-    parsed
+enum Operator {
+    Command(Command),
+    /// `&&`, the right side only runs if the left side is not 0
+    And,
+    /// `||`, the right side only runs if the left side is 0
+    Or
 }
 
-fn parse_expression(prog: &[&str], i: &mut usize) -> Vec<Command> {
-    let mut parsed: Vec<Command> = vec![];
-
-    match prog[*i] {
-        "(" => parsed.append(&mut parse_binary_expression(prog, i)),
-        _ => parsed.append(&mut parse_value(prog, i)),
-    }
+/// binding strength and meaning of a binary operator, higher binds tighter
+fn binary_operator(text: &str) -> Option<(u8, Operator)> {
+    Some(match text {
+        "||" => (1, Operator::Or),
+        "&&" => (2, Operator::And),
+        "==" => (3, Operator::Command(Command::EQ)),
+        "!=" => (3, Operator::Command(Command::NE)),
+        "<" => (4, Operator::Command(Command::LT)),
+        "<=" => (4, Operator::Command(Command::LE)),
+        ">" => (4, Operator::Command(Command::GT)),
+        ">=" => (4, Operator::Command(Command::GE)),
+        "|" => (5, Operator::Command(Command::BitOr)),
+        "^" => (6, Operator::Command(Command::BitXor)),
+        "&" => (7, Operator::Command(Command::BitAnd)),
+        "<<" => (8, Operator::Command(Command::Shl)),
+        ">>" => (8, Operator::Command(Command::Shr)),
+        "+" => (9, Operator::Command(Command::Add)),
+        "-" => (9, Operator::Command(Command::Sub)),
+        "*" => (10, Operator::Command(Command::Mul)),
+        "/" => (10, Operator::Command(Command::Div)),
+        "div" => (10, Operator::Command(Command::IntDiv)),
+        "%" => (10, Operator::Command(Command::Mod)),
+        _ => return None
+    })
+}
 
-    parsed
+/// Parse the parenthesized expression starting at `prog[*i]`.
+///
+/// Operators follow the usual precedence, from loosest to tightest: `||`, `&&`, `==` `!=`,
+/// `<` `<=` `>` `>=`, `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/` `div` `%` and the prefix operators
+/// `-`, `~` and `not`. All binary operators are left associative. Afterwards `*i` points to the closing parenthesis.
+pub fn parse(prog: &[Token], i: &mut usize) -> ParseResult {
+    let mut parser = Parser { prog, i: *i };
+    let parsed = parser.parenthesized()?;
+
+    *i = parser.i - 1;
+    Ok(parsed)
 }
 
-fn parse_binary_expression(prog: &[&str], i: &mut usize) -> Vec<Command> {
-    let mut parsed: Vec<Command> = vec![];
+struct Parser<'a> {
+    prog: &'a [Token],
+    i: usize
+}
 
-    if prog[*i] != "(" {
-        panic!("expected opening parenthesis, found {}", prog[*i]);
+impl<'a> Parser<'a> {
+    /// the current token, running out of tokens means the parenthesis opened at `start` is never closed
+    fn token(&self, start: &Span) -> Result<&'a Token, (String, Span)> {
+        self.prog.get(self.i).ok_or_else(|| (String::from("unmatched `(`"), start.clone()))
     }
-    *i += 1;
-
-    parsed.append(&mut parse_expression(prog, i));
-
-    let op = match prog[*i] {
-        "+" => Command::Add,
-        "-" => Command::Sub,
-        "*" => Command::Mul,
-        "/" => Command::Div,
-        ">" => Command::GT,
-        ">=" => Command::GE,
-        "<" => Command::LT,
-        "<=" => Command::LE,
-        "==" => Command::EQ,
-        "!=" => Command::NE,
-        _ => {
-            panic!("invalid operator: {}", prog[*i]);
-        }
-    };
-    *i += 1;
 
-    parsed.append(&mut parse_expression(prog, i));
-    parsed.push(op);
+    fn parenthesized(&mut self) -> ParseResult {
+        let start = self.prog[self.i].span.clone();
+        self.i += 1;
 
-    if prog[*i] != ")" {
-        parsed.append(&mut parse_expression_continuation(prog, i));
+        let parsed = self.expression(0, &start)?;
+
+        let token = self.token(&start)?;
+        if token.text != ")" {
+            return Err((format!("invalid operator: `{}`", token.text), token.span.clone()));
+        }
+        self.i += 1;
+
+        Ok(parsed)
     }
-    *i += 1;
 
-    parsed
-}
+    /// an expression of operators that bind at least as strong as `min_precedence`
+    fn expression(&mut self, min_precedence: u8, start: &Span) -> ParseResult {
+        let mut parsed = self.unary(start)?;
 
-fn parse_expression_continuation(prog: &[&str], i: &mut usize) -> Vec<Command> {
-    println!("Notherlang: operator precedence is not supported, use parentheses instead!");
-
-    let mut parsed: Vec<Command> = vec![];
-
-    let op = match prog[*i] {
-        "+" => Command::Add,
-        "-" => Command::Sub,
-        "*" => Command::Mul,
-        "/" => Command::Div,
-        ">" => Command::GT,
-        ">=" => Command::GE,
-        "<" => Command::LT,
-        "<=" => Command::LE,
-        "==" => Command::EQ,
-        "!=" => Command::NE,
-        _ => {
-            panic!("invalid operator: {}", prog[*i]);
-        }
-    };
-    *i += 1;
+        while let Some(token) = self.prog.get(self.i) {
+            let (precedence, operator) = match binary_operator(&token.text) {
+                Some((precedence, operator)) if precedence >= min_precedence => (precedence, operator),
+                _ => break
+            };
+            self.i += 1;
 
-    parsed.append(&mut parse_expression(prog, i));
-    parsed.push(op);
+            let right = self.expression(precedence + 1, start)?;
+            parsed = combine(parsed, operator, right, token);
+        }
 
-    if prog[*i] != ")" {
-        parsed.append(&mut parse_expression_continuation(prog, i));
+        Ok(parsed)
     }
 
-    parsed
+    fn unary(&mut self, start: &Span) -> ParseResult {
+        let token = self.token(start)?;
+        let source = SourceReference::Visible(token.text.clone(), token.span.clone());
+
+        match token.text.as_str() {
+            "(" => self.parenthesized(),
+            "-" => {
+                self.i += 1;
+                let mut parsed = vec![(Command::Pushi(0), SourceReference::Invisible(token.span.clone()))];
+                parsed.append(&mut self.unary(start)?);
+                parsed.push((Command::Sub, source));
+                Ok(parsed)
+            },
+            "not" => {
+                self.i += 1;
+                let mut parsed = self.unary(start)?;
+                parsed.push((Command::Not, source));
+                Ok(parsed)
+            },
+            "~" => {
+                self.i += 1;
+                let mut parsed = self.unary(start)?;
+                parsed.push((Command::BitNot, source));
+                Ok(parsed)
+            },
+            text if text == ")" || binary_operator(text).is_some() =>
+                Err((format!("expected a value, found `{}`", text), token.span.clone())),
+            _ => {
+                self.i += 1;
+                Ok(value(token))
+            }
+        }
+    }
 }
 
-fn parse_value(prog: &[&str], i: &mut usize) -> Vec<Command> {
-    let mut parsed: Vec<Command> = vec![];
+/// the commands for `left operator right`
+fn combine(mut left: Parsed, operator: Operator, mut right: Parsed, token: &Token) -> Parsed {
+    let source = SourceReference::Visible(token.text.clone(), token.span.clone());
+    let invisible = || SourceReference::Invisible(token.span.clone());
 
-    match prog[*i] {
-        n if n.parse::<f64>().is_ok() => {
-            parsed.push(Command::Pushn(prog[*i].parse::<f64>().unwrap()));
-            *i += 1;
-        },
-        s if s.starts_with('@') && s.ends_with('!') => {
-            let mut name = String::from(s);
-            name.pop();
-            parsed.push(Command::NamedReference(name, 0));
-            parsed.push(Command::Jmp);
-            *i += 1;
-        },
-        s if s.starts_with('@') && s.ends_with('$') => {
-            let mut name = String::from(s);
-            name.pop();
-            parsed.push(Command::NamedReference(name, 0));
-            parsed.push(Command::Get);
-            *i += 1;
+    match operator {
+        Operator::Command(command) => {
+            left.append(&mut right);
+            left.push((command, source));
         },
-        s if s.starts_with('@') => {
-            parsed.push(Command::NamedReference(s.into(), 0));
-            *i += 1;
-        }
-        s => {
-            parsed.push(Command::Pushs(s.into()));
-            *i += 1;
+        // keep the left value if it decides the result, otherwise replace it with the right one
+        Operator::And | Operator::Or => {
+            let skip = if let Operator::And = operator {
+                Command::SkipIfZero(right.len() + 1)
+            } else {
+                Command::SkipIfNotZero(right.len() + 1)
+            };
+            left.push((Command::Dup, invisible()));
+            left.push((skip, source));
+            left.push((Command::Drop, invisible()));
+            left.append(&mut right);
+            // turn the value into 1 or 0
+            left.push((Command::Not, invisible()));
+            left.push((Command::Not, invisible()));
         }
     }
-
-    parsed
+    left
 }
 
+fn value(token: &Token) -> Parsed {
+    let span = token.span.clone();
+    let source = SourceReference::Visible(token.text.clone(), span.clone());
+
+    match token.text.as_str() {
+        n if n.parse::<i64>().is_ok() => vec![(Command::Pushi(n.parse::<i64>().unwrap()), source)],
+        n if n.parse::<f64>().is_ok() => vec![(Command::Pushn(n.parse::<f64>().unwrap()), source)],
+        s if s.starts_with('@') => reference(s, &span),
+        s if s.starts_with('"') => vec![(Command::Pushs(string_literal(s)), source)],
+        "\\space" => vec![(Command::Pushs(String::from(" ")), source)],
+        s => vec![(Command::Pushs(s.into()), source)]
+    }
+}
@@ -0,0 +1,693 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use regex::Regex;
+
+use crate::{code_point, integer, slots_equal, Environment, ErrorKind, MapKey, RuntimeError, StackSlot, MAX_RESERVED_CELLS};
+
+
+/// the string that marks the start of a list on the stack in `lists.hr`
+const GUARD: &str = "|";
+
+/// the pattern cache is emptied when it grows beyond this
+const MAX_CACHED_PATTERNS: usize = 256;
+
+/// Native functions that every `Environment` starts with.
+pub(crate) fn register(env: &mut Environment) {
+    env.register_native("assert", assert);
+    env.register_native("assert-eq", assert_eq);
+    env.register_native("throw", throw);
+
+    env.register_native("len", len);
+    env.register_native("at", at);
+    env.register_native("push", push);
+    env.register_native("pop", pop);
+    env.register_native("slice", slice);
+    env.register_native("concat", concat);
+    env.register_native("reverse", reverse);
+    env.register_native("list", list);
+    env.register_native("spread", spread);
+
+    env.register_native("empty-map", empty_map);
+    env.register_native("to-map", to_map);
+    env.register_native("insert", insert);
+    env.register_native("lookup", lookup);
+    env.register_native("remove", remove);
+    env.register_native("contains", contains);
+    env.register_native("keys", keys);
+    env.register_native("values", values);
+    env.register_native("entries", entries);
+
+    env.register_native("alloc", alloc);
+    env.register_native("gc", gc);
+    env.register_native("heap-stats", heap_stats);
+
+    env.register_native("argv", argv);
+    env.register_native("exit", exit);
+    env.register_native("stack-floor", stack_floor);
+    env.register_native("eprint", eprint);
+
+    env.register_native("char-at", char_at);
+    env.register_native("substring", substring);
+    env.register_native("find", find);
+    env.register_native("split", split);
+    env.register_native("join", join);
+    env.register_native("trim", trim);
+    env.register_native("upper", upper);
+    env.register_native("lower", lower);
+    env.register_native("starts-with", starts_with);
+    env.register_native("ends-with", ends_with);
+    env.register_native("char", char);
+    env.register_native("code-point", code_point_of);
+    env.register_native("to-string", to_string);
+    env.register_native("parse-number", parse_number);
+    env.register_native("format-number", format_number);
+    env.register_native("to-bytes", to_bytes);
+    env.register_native("from-bytes", from_bytes);
+
+    env.register_native("re-match", re_match);
+    env.register_native("re-find-all", re_find_all);
+    env.register_native("re-captures", re_captures);
+    env.register_native("re-replace", re_replace);
+    env.register_native("re-split", re_split);
+}
+
+/// ( condition -- ) fails unless the condition is a number other than 0
+fn assert(env: &mut Environment) -> Result<(), RuntimeError> {
+    match env.stack.pop() {
+        Some(StackSlot::Number(n)) if n != 0.0 => Ok(()),
+        Some(StackSlot::Int(n)) if n != 0 => Ok(()),
+        Some(zero @ StackSlot::Number(_)) | Some(zero @ StackSlot::Int(_)) =>
+            Err(env.error(ErrorKind::AssertionFailed, "assertion failed").with_operands(vec![zero])),
+        Some(other) => Err(env.error(ErrorKind::TypeMismatch, "assert needs a number")
+            .with_operands(vec![other])),
+        None => Err(env.error(ErrorKind::StackUnderflow, "assert needs a value"))
+    }
+}
+
+/// ( actual expected -- ) fails unless both values are equal in the sense of `==`
+fn assert_eq(env: &mut Environment) -> Result<(), RuntimeError> {
+    let (expected, actual) = match (env.stack.pop(), env.stack.pop()) {
+        (Some(expected), Some(actual)) => (expected, actual),
+        _ => return Err(env.error(ErrorKind::StackUnderflow, "assert-eq needs two values"))
+    };
+
+    if !slots_equal(&actual, &expected) {
+        return Err(env.error(ErrorKind::AssertionFailed, format!("expected `{}` but got `{}`", expected, actual))
+            .with_operands(vec![actual, expected]));
+    }
+    Ok(())
+}
+
+fn pop_list(env: &mut Environment, name: &str) -> Result<Vec<StackSlot>, RuntimeError> {
+    match env.stack.pop() {
+        Some(StackSlot::List(items)) => Ok(items),
+        Some(other) => Err(env.error(ErrorKind::TypeMismatch, format!("{} needs a list", name))
+            .with_operands(vec![other])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs a list", name)))
+    }
+}
+
+fn pop_integer(env: &mut Environment, name: &str) -> Result<i64, RuntimeError> {
+    match env.stack.pop() {
+        Some(slot) => integer(&slot).ok_or_else(|| env.error(ErrorKind::TypeMismatch, format!("{} needs an integer", name))
+            .with_operands(vec![slot])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs an integer", name)))
+    }
+}
+
+/// Turn `index` into a position in a list of `len` items or a string of `len` characters, negative
+/// indexes count from the end.
+/// With `end` the position right after the last item is allowed as well.
+fn position(env: &Environment, index: i64, len: usize, end: bool) -> Result<usize, RuntimeError> {
+    let position = if index < 0 { len as i64 + index } else { index };
+    let last = if end { len as i64 } else { len as i64 - 1 };
+
+    if position < 0 || position > last {
+        return Err(env.error(ErrorKind::IndexOutOfRange, format!("index {} is out of range for length {}", index, len)));
+    }
+    Ok(position as usize)
+}
+
+/// ( list -- length ), ( map -- length ) or ( string -- length ) with the length in characters
+fn len(env: &mut Environment) -> Result<(), RuntimeError> {
+    let len = match env.stack.pop() {
+        Some(StackSlot::List(items)) => items.len(),
+        Some(StackSlot::Map(entries)) => entries.len(),
+        Some(StackSlot::String(s)) => s.chars().count(),
+        Some(other) => return Err(env.error(ErrorKind::TypeMismatch, "len needs a list, a map or a string")
+            .with_operands(vec![other])),
+        None => return Err(env.error(ErrorKind::StackUnderflow, "len needs a list, a map or a string"))
+    };
+    env.stack.push(StackSlot::Int(len as i64));
+    Ok(())
+}
+
+/// ( list index -- item ) negative indexes count from the end
+fn at(env: &mut Environment) -> Result<(), RuntimeError> {
+    let index = pop_integer(env, "at")?;
+    let mut items = pop_list(env, "at")?;
+
+    let position = position(env, index, items.len(), false)?;
+    env.stack.push(items.swap_remove(position));
+    Ok(())
+}
+
+/// ( list item -- list ) add the item to the end
+fn push(env: &mut Environment) -> Result<(), RuntimeError> {
+    let item = env.stack.pop().ok_or_else(|| env.error(ErrorKind::StackUnderflow, "push needs an item"))?;
+    let mut items = pop_list(env, "push")?;
+
+    items.push(item);
+    env.stack.push(StackSlot::List(items));
+    Ok(())
+}
+
+/// ( list -- list item ) remove the last item
+fn pop(env: &mut Environment) -> Result<(), RuntimeError> {
+    let mut items = pop_list(env, "pop")?;
+    let item = items.pop().ok_or_else(|| env.error(ErrorKind::IndexOutOfRange, "can't pop from an empty list"))?;
+
+    env.stack.push(StackSlot::List(items));
+    env.stack.push(item);
+    Ok(())
+}
+
+/// ( list from to -- list ) the items from `from` up to but not including `to`
+fn slice(env: &mut Environment) -> Result<(), RuntimeError> {
+    let to = pop_integer(env, "slice")?;
+    let from = pop_integer(env, "slice")?;
+    let items = pop_list(env, "slice")?;
+
+    let start = position(env, from, items.len(), true)?;
+    let end = position(env, to, items.len(), true)?;
+    if start > end {
+        return Err(env.error(ErrorKind::IndexOutOfRange, format!("slice from {} to {} ends before it starts", from, to)));
+    }
+    env.stack.push(StackSlot::List(items[start..end].to_vec()));
+    Ok(())
+}
+
+/// ( list list -- list )
+fn concat(env: &mut Environment) -> Result<(), RuntimeError> {
+    let mut second = pop_list(env, "concat")?;
+    let mut items = pop_list(env, "concat")?;
+
+    items.append(&mut second);
+    env.stack.push(StackSlot::List(items));
+    Ok(())
+}
+
+/// ( list -- list )
+fn reverse(env: &mut Environment) -> Result<(), RuntimeError> {
+    let mut items = pop_list(env, "reverse")?;
+    items.reverse();
+    env.stack.push(StackSlot::List(items));
+    Ok(())
+}
+
+/// ( | items -- list ) collect the items above the `|` guard of `lists.hr` into a list
+fn list(env: &mut Environment) -> Result<(), RuntimeError> {
+    let guard = env.stack.stack.iter()
+        .rposition(|slot| matches!(slot, StackSlot::String(s) if s == GUARD))
+        .ok_or_else(|| env.error(ErrorKind::InvalidOperation, "list needs a `|` guard on the stack"))?;
+
+    let items = env.stack.stack.split_off(guard + 1);
+    env.stack.pop();
+    env.stack.push(StackSlot::List(items));
+    Ok(())
+}
+
+/// ( list -- | items ) the opposite of `list`, for the functions in `lists.hr`
+fn spread(env: &mut Environment) -> Result<(), RuntimeError> {
+    let items = pop_list(env, "spread")?;
+    env.stack.push(StackSlot::String(String::from(GUARD)));
+    env.stack.stack.extend(items);
+    Ok(())
+}
+
+fn pop_map(env: &mut Environment, name: &str) -> Result<BTreeMap<MapKey, StackSlot>, RuntimeError> {
+    match env.stack.pop() {
+        Some(StackSlot::Map(entries)) => Ok(entries),
+        Some(other) => Err(env.error(ErrorKind::TypeMismatch, format!("{} needs a map", name))
+            .with_operands(vec![other])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs a map", name)))
+    }
+}
+
+fn pop_key(env: &mut Environment, name: &str) -> Result<MapKey, RuntimeError> {
+    match env.stack.pop() {
+        Some(slot) => MapKey::from_slot(&slot).ok_or_else(|| env.error(ErrorKind::TypeMismatch, "map keys are strings or numbers")
+            .with_operands(vec![slot])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs a key", name)))
+    }
+}
+
+/// ( -- map )
+fn empty_map(env: &mut Environment) -> Result<(), RuntimeError> {
+    env.stack.push(StackSlot::Map(BTreeMap::new()));
+    Ok(())
+}
+
+/// ( list -- map ) the list holds `[key value]` pairs, like the result of `entries`
+fn to_map(env: &mut Environment) -> Result<(), RuntimeError> {
+    let mut entries = BTreeMap::new();
+
+    for pair in pop_list(env, "to-map")? {
+        match pair {
+            StackSlot::List(mut pair) if pair.len() == 2 => {
+                let value = pair.pop().unwrap();
+                let key = MapKey::from_slot(&pair[0]).ok_or_else(|| env.error(ErrorKind::TypeMismatch, "map keys are strings or numbers")
+                    .with_operands(vec![pair[0].clone()]))?;
+                entries.insert(key, value);
+            },
+            other => return Err(env.error(ErrorKind::TypeMismatch, "to-map needs a list of `[key value]` pairs")
+                .with_operands(vec![other]))
+        }
+    }
+    env.stack.push(StackSlot::Map(entries));
+    Ok(())
+}
+
+/// ( map key value -- map ) replaces the value of a key that is already in the map
+fn insert(env: &mut Environment) -> Result<(), RuntimeError> {
+    let value = env.stack.pop().ok_or_else(|| env.error(ErrorKind::StackUnderflow, "insert needs a value"))?;
+    let key = pop_key(env, "insert")?;
+    let mut entries = pop_map(env, "insert")?;
+
+    entries.insert(key, value);
+    env.stack.push(StackSlot::Map(entries));
+    Ok(())
+}
+
+/// ( map key -- value )
+fn lookup(env: &mut Environment) -> Result<(), RuntimeError> {
+    let key = pop_key(env, "lookup")?;
+    let mut entries = pop_map(env, "lookup")?;
+
+    let value = entries.remove(&key).ok_or_else(|| env.error(ErrorKind::KeyNotFound, format!("no key {:?} in the map", key)))?;
+    env.stack.push(value);
+    Ok(())
+}
+
+/// ( map key -- map ) removing a key that is not in the map does nothing
+fn remove(env: &mut Environment) -> Result<(), RuntimeError> {
+    let key = pop_key(env, "remove")?;
+    let mut entries = pop_map(env, "remove")?;
+
+    entries.remove(&key);
+    env.stack.push(StackSlot::Map(entries));
+    Ok(())
+}
+
+/// ( map key -- flag ) 1 if the key is in the map, otherwise 0
+fn contains(env: &mut Environment) -> Result<(), RuntimeError> {
+    let key = pop_key(env, "contains")?;
+    let entries = pop_map(env, "contains")?;
+
+    env.stack.push(StackSlot::Int(entries.contains_key(&key).into()));
+    Ok(())
+}
+
+/// ( map -- list ) the keys in order
+fn keys(env: &mut Environment) -> Result<(), RuntimeError> {
+    let entries = pop_map(env, "keys")?;
+    env.stack.push(StackSlot::List(entries.keys().map(MapKey::to_slot).collect()));
+    Ok(())
+}
+
+/// ( map -- list ) the values in the order of their keys
+fn values(env: &mut Environment) -> Result<(), RuntimeError> {
+    let entries = pop_map(env, "values")?;
+    env.stack.push(StackSlot::List(entries.into_values().collect()));
+    Ok(())
+}
+
+/// ( map -- list ) a `[key value]` list for every entry, in the order of the keys
+fn entries(env: &mut Environment) -> Result<(), RuntimeError> {
+    let entries = pop_map(env, "entries")?;
+    env.stack.push(StackSlot::List(entries.into_iter()
+        .map(|(key, value)| StackSlot::List(vec![key.to_slot(), value]))
+        .collect()));
+    Ok(())
+}
+
+/// ( size -- reference ) a new heap block with `size` cells that hold 0
+fn alloc(env: &mut Environment) -> Result<(), RuntimeError> {
+    let size = pop_integer(env, "alloc")?;
+    let size = usize::try_from(size)
+        .map_err(|_| env.error(ErrorKind::InvalidOperation, format!("can't allocate {} cells", size)))?;
+
+    if size > MAX_RESERVED_CELLS {
+        return Err(env.error(ErrorKind::LimitExceeded,
+            format!("limit exceeded: can't allocate {} cells, a block holds at most {}", size, MAX_RESERVED_CELLS)));
+    }
+
+    if env.heap.wants_collection() {
+        env.collect_garbage();
+    }
+    if let Some(max) = env.limits.heap_size {
+        if env.heap.stats().cells + size > max {
+            env.collect_garbage();
+        }
+        if env.heap.stats().cells + size > max {
+            return Err(env.error(ErrorKind::LimitExceeded,
+                format!("limit exceeded: the heap would hold more than {} cells", max)));
+        }
+    }
+    let block = env.heap.alloc(size);
+    env.stack.push(StackSlot::HeapReference(block, 0));
+    Ok(())
+}
+
+/// ( -- ) free the heap blocks that are no longer referenced
+fn gc(env: &mut Environment) -> Result<(), RuntimeError> {
+    env.collect_garbage();
+    Ok(())
+}
+
+/// ( value -- ) raise an error, a string becomes its message, `catch` finds the value under `value`
+fn throw(env: &mut Environment) -> Result<(), RuntimeError> {
+    let value = env.stack.pop()
+        .ok_or_else(|| env.error(ErrorKind::StackUnderflow, "throw needs a value"))?;
+    let message = match &value {
+        StackSlot::String(s) => s.clone(),
+        other => other.to_string()
+    };
+    Err(env.error(ErrorKind::Thrown, message).with_operands(vec![value]))
+}
+
+/// ( -- list ) the command line arguments that follow the script
+fn argv(env: &mut Environment) -> Result<(), RuntimeError> {
+    env.stack.push(StackSlot::List(env.args.iter().cloned().map(StackSlot::String).collect()));
+    Ok(())
+}
+
+/// ( code -- ) stop the script, the embedder reads the code from `Environment::exit_code`
+fn exit(env: &mut Environment) -> Result<(), RuntimeError> {
+    let code = pop_integer(env, "exit")?;
+    let code = i32::try_from(code)
+        .map_err(|_| env.error(ErrorKind::InvalidOperation, format!("exit code {} is out of range", code)))?;
+
+    env.exit_code = Some(code);
+    env.unwind(0);
+    env.idx = env.program.len();
+    Ok(())
+}
+
+/// ( -- ) the values on the stack now aren't arguments for parameters, `std.hr` keeps its guard
+/// below them
+fn stack_floor(env: &mut Environment) -> Result<(), RuntimeError> {
+    env.stack_floor = env.stack.stack.len();
+    Ok(())
+}
+
+/// ( value -- ) like `print`, but to stderr
+fn eprint(env: &mut Environment) -> Result<(), RuntimeError> {
+    let value = env.stack.pop()
+        .ok_or_else(|| env.error(ErrorKind::StackUnderflow, "eprint needs a value"))?;
+    env.io.borrow_mut().eprint(&value.to_string());
+    Ok(())
+}
+
+/// ( -- map ) the counters of `HeapStats`
+fn heap_stats(env: &mut Environment) -> Result<(), RuntimeError> {
+    let stats = env.heap_stats();
+    let counters = [
+        ("blocks", stats.blocks as i64),
+        ("cells", stats.cells as i64),
+        ("allocated", stats.allocated as i64),
+        ("freed", stats.freed as i64),
+        ("collections", stats.collections as i64)
+    ];
+
+    let entries = counters.iter()
+        .map(|(name, value)| (MapKey::String(name.to_string()), StackSlot::Int(*value)))
+        .collect();
+    env.stack.push(StackSlot::Map(entries));
+    Ok(())
+}
+
+pub(crate) fn pop_string(env: &mut Environment, name: &str) -> Result<String, RuntimeError> {
+    match env.stack.pop() {
+        Some(StackSlot::String(s)) => Ok(s),
+        Some(other) => Err(env.error(ErrorKind::TypeMismatch, format!("{} needs a string", name))
+            .with_operands(vec![other])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs a string", name)))
+    }
+}
+
+fn push_flag(env: &mut Environment, flag: bool) {
+    env.stack.push(StackSlot::Int(flag.into()));
+}
+
+/// ( string index -- character ) negative indexes count from the end
+fn char_at(env: &mut Environment) -> Result<(), RuntimeError> {
+    let index = pop_integer(env, "char-at")?;
+    let s = pop_string(env, "char-at")?;
+
+    let position = position(env, index, s.chars().count(), false)?;
+    env.stack.push(StackSlot::String(s.chars().nth(position).unwrap().to_string()));
+    Ok(())
+}
+
+/// ( string from to -- string ) the characters from `from` up to but not including `to`
+fn substring(env: &mut Environment) -> Result<(), RuntimeError> {
+    let to = pop_integer(env, "substring")?;
+    let from = pop_integer(env, "substring")?;
+    let s = pop_string(env, "substring")?;
+
+    let len = s.chars().count();
+    let start = position(env, from, len, true)?;
+    let end = position(env, to, len, true)?;
+    if start > end {
+        return Err(env.error(ErrorKind::IndexOutOfRange, format!("substring from {} to {} ends before it starts", from, to)));
+    }
+    env.stack.push(StackSlot::String(s.chars().skip(start).take(end - start).collect()));
+    Ok(())
+}
+
+/// ( string part -- index ) the character index of the first occurrence of `part`, -1 if there is none
+fn find(env: &mut Environment) -> Result<(), RuntimeError> {
+    let part = pop_string(env, "find")?;
+    let s = pop_string(env, "find")?;
+
+    let index = s.find(&part).map_or(-1, |byte| s[..byte].chars().count() as i64);
+    env.stack.push(StackSlot::Int(index));
+    Ok(())
+}
+
+/// ( string separator -- list ) an empty separator splits into characters
+fn split(env: &mut Environment) -> Result<(), RuntimeError> {
+    let separator = pop_string(env, "split")?;
+    let s = pop_string(env, "split")?;
+
+    let parts: Vec<StackSlot> = if separator.is_empty() {
+        s.chars().map(|c| StackSlot::String(c.to_string())).collect()
+    } else {
+        s.split(&separator).map(|part| StackSlot::String(part.into())).collect()
+    };
+    env.stack.push(StackSlot::List(parts));
+    Ok(())
+}
+
+/// ( list separator -- string ) items that are not strings are joined as `print` shows them
+fn join(env: &mut Environment) -> Result<(), RuntimeError> {
+    let separator = pop_string(env, "join")?;
+    let items = pop_list(env, "join")?;
+
+    let parts: Vec<String> = items.iter().map(StackSlot::to_string).collect();
+    env.stack.push(StackSlot::String(parts.join(&separator)));
+    Ok(())
+}
+
+/// ( string -- string ) without whitespace at the start and the end
+fn trim(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "trim")?;
+    env.stack.push(StackSlot::String(s.trim().into()));
+    Ok(())
+}
+
+/// ( string -- string )
+fn upper(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "upper")?;
+    env.stack.push(StackSlot::String(s.to_uppercase()));
+    Ok(())
+}
+
+/// ( string -- string )
+fn lower(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "lower")?;
+    env.stack.push(StackSlot::String(s.to_lowercase()));
+    Ok(())
+}
+
+/// ( string prefix -- flag )
+fn starts_with(env: &mut Environment) -> Result<(), RuntimeError> {
+    let prefix = pop_string(env, "starts-with")?;
+    let s = pop_string(env, "starts-with")?;
+    push_flag(env, s.starts_with(&prefix));
+    Ok(())
+}
+
+/// ( string suffix -- flag )
+fn ends_with(env: &mut Environment) -> Result<(), RuntimeError> {
+    let suffix = pop_string(env, "ends-with")?;
+    let s = pop_string(env, "ends-with")?;
+    push_flag(env, s.ends_with(&suffix));
+    Ok(())
+}
+
+/// ( code-point -- character )
+fn char(env: &mut Environment) -> Result<(), RuntimeError> {
+    let code = pop_integer(env, "char")?;
+    let c = code_point(env, code)?;
+    env.stack.push(StackSlot::String(c.to_string()));
+    Ok(())
+}
+
+/// ( character -- code-point )
+fn code_point_of(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "code-point")?;
+    let mut chars = s.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => env.stack.push(StackSlot::Int(u32::from(c).into())),
+        _ => return Err(env.error(ErrorKind::Conversion, "code-point needs a string with exactly one character")
+            .with_operands(vec![StackSlot::String(s)]))
+    }
+    Ok(())
+}
+
+/// ( value -- string ) the text `print` would show
+fn to_string(env: &mut Environment) -> Result<(), RuntimeError> {
+    let value = env.stack.pop().ok_or_else(|| env.error(ErrorKind::StackUnderflow, "to-string needs a value"))?;
+    env.stack.push(StackSlot::String(value.to_string()));
+    Ok(())
+}
+
+/// ( string -- number ) an integer if the text is one, otherwise a float
+fn parse_number(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "parse-number")?;
+    let text = s.trim();
+
+    let number = match (text.parse::<i64>(), text.parse::<f64>()) {
+        (Ok(n), _) => StackSlot::Int(n),
+        (_, Ok(n)) => StackSlot::Number(n),
+        _ => return Err(env.error(ErrorKind::Conversion, format!("`{}` is not a number", s))
+            .with_operands(vec![StackSlot::String(s)]))
+    };
+    env.stack.push(number);
+    Ok(())
+}
+
+/// the most digits `format-number` shows after the point, a float has no more than that to show
+const MAX_DECIMALS: usize = 100;
+
+/// ( number decimals -- string ) the number with a fixed count of digits after the point
+fn format_number(env: &mut Environment) -> Result<(), RuntimeError> {
+    let decimals = pop_integer(env, "format-number")?;
+    let decimals = usize::try_from(decimals).ok()
+        .filter(|&decimals| decimals <= MAX_DECIMALS)
+        .ok_or_else(|| env.error(ErrorKind::InvalidOperation, format!("can't show {} decimals, at most {} are possible", decimals, MAX_DECIMALS)))?;
+    let n = env.stack.pop_number().ok_or_else(|| env.error(ErrorKind::TypeMismatch, "format-number needs a number"))?;
+
+    env.stack.push(StackSlot::String(format!("{:.*}", decimals, n)));
+    Ok(())
+}
+
+/// ( string -- list ) the UTF-8 bytes of the string
+fn to_bytes(env: &mut Environment) -> Result<(), RuntimeError> {
+    let s = pop_string(env, "to-bytes")?;
+    env.stack.push(StackSlot::List(s.bytes().map(|byte| StackSlot::Int(byte.into())).collect()));
+    Ok(())
+}
+
+/// ( list -- string ) decode a list of UTF-8 bytes, like the ones from `to-bytes` or `__bytes`
+fn from_bytes(env: &mut Environment) -> Result<(), RuntimeError> {
+    let items = pop_list(env, "from-bytes")?;
+
+    let mut bytes = Vec::with_capacity(items.len());
+    for item in items {
+        match integer(&item).and_then(|n| u8::try_from(n).ok()) {
+            Some(byte) => bytes.push(byte),
+            None => return Err(env.error(ErrorKind::Conversion, "from-bytes needs numbers from 0 to 255")
+                .with_operands(vec![item]))
+        }
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(s) => env.stack.push(StackSlot::String(s)),
+        Err(err) => return Err(env.error(ErrorKind::Conversion,
+            format!("invalid UTF-8 at byte {}", err.utf8_error().valid_up_to())))
+    }
+    Ok(())
+}
+
+/// the compiled pattern on top of the stack, patterns are only compiled the first time they are used
+fn pop_regex(env: &mut Environment, name: &str) -> Result<Regex, RuntimeError> {
+    let pattern = pop_string(env, name)?;
+    if let Some(regex) = env.regexes.get(&pattern) {
+        return Ok(regex.clone());
+    }
+
+    let regex = Regex::new(&pattern).map_err(|err| env.error(ErrorKind::InvalidPattern, format!("invalid pattern: {}", err))
+        .with_operands(vec![StackSlot::String(pattern.clone())]))?;
+    if env.regexes.len() >= MAX_CACHED_PATTERNS {
+        env.regexes.clear();
+    }
+    env.regexes.insert(pattern, regex.clone());
+    Ok(regex)
+}
+
+fn strings(parts: impl Iterator<Item = impl Into<String>>) -> StackSlot {
+    StackSlot::List(parts.map(|part| StackSlot::String(part.into())).collect())
+}
+
+/// ( input pattern -- flag ) 1 if the pattern matches somewhere in the input
+fn re_match(env: &mut Environment) -> Result<(), RuntimeError> {
+    let regex = pop_regex(env, "re-match")?;
+    let input = pop_string(env, "re-match")?;
+    push_flag(env, regex.is_match(&input));
+    Ok(())
+}
+
+/// ( input pattern -- list ) every match of the pattern
+fn re_find_all(env: &mut Environment) -> Result<(), RuntimeError> {
+    let regex = pop_regex(env, "re-find-all")?;
+    let input = pop_string(env, "re-find-all")?;
+    env.stack.push(strings(regex.find_iter(&input).map(|m| m.as_str())));
+    Ok(())
+}
+
+/// ( input pattern -- list ) the whole first match followed by its groups, groups that didn't take
+/// part in the match are empty strings, the list is empty if there is no match
+fn re_captures(env: &mut Environment) -> Result<(), RuntimeError> {
+    let regex = pop_regex(env, "re-captures")?;
+    let input = pop_string(env, "re-captures")?;
+
+    let groups = match regex.captures(&input) {
+        Some(captures) => strings(captures.iter().map(|group| group.map_or("", |m| m.as_str()))),
+        None => StackSlot::List(Vec::new())
+    };
+    env.stack.push(groups);
+    Ok(())
+}
+
+/// ( input pattern replacement -- string ) replace every match, `$1` or `${name}` in the
+/// replacement stand for a group
+fn re_replace(env: &mut Environment) -> Result<(), RuntimeError> {
+    let replacement = pop_string(env, "re-replace")?;
+    let regex = pop_regex(env, "re-replace")?;
+    let input = pop_string(env, "re-replace")?;
+    env.stack.push(StackSlot::String(regex.replace_all(&input, replacement.as_str()).into_owned()));
+    Ok(())
+}
+
+/// ( input pattern -- list ) the parts between the matches
+fn re_split(env: &mut Environment) -> Result<(), RuntimeError> {
+    let regex = pop_regex(env, "re-split")?;
+    let input = pop_string(env, "re-split")?;
+    env.stack.push(strings(regex.split(&input)));
+    Ok(())
+}
@@ -0,0 +1,590 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::{find_include, index_locals, load, run, splice, Command, Environment, Error, ErrorKind, MapKey, SourceFile,
+    RuntimeError, SourceReference, Span, SyntaxError, Visibility};
+
+
+/// first bytes of every compiled file
+const MAGIC: &[u8; 4] = b"HRBC";
+
+/// bumped whenever the layout of compiled files changes
+const FORMAT_VERSION: u32 = 2;
+
+/// Commands without operands, a command is stored as its index in this list.
+///
+/// Only ever append to this list, the position is part of the file format.
+const SIMPLE_COMMANDS: &[Command] = &[
+    Command::EndDefine, Command::Jmp, Command::JmpIf, Command::LoopIf,
+    Command::Add, Command::Sub, Command::Mul, Command::Div, Command::Mod,
+    Command::GT, Command::GE, Command::LT, Command::LE, Command::EQ, Command::NE,
+    Command::Print, Command::Getc, Command::Not, Command::Dup, Command::Swap, Command::Drop,
+    Command::Put, Command::Get, Command::ArrowPut, Command::AddressOf, Command::Return,
+    Command::Pull, Command::Sleep, Command::Nop, Command::Include, Command::PrintStack,
+    Command::Bytes, Command::Placeholder, Command::Breakpoint,
+    Command::IntDiv, Command::BitAnd, Command::BitOr, Command::BitXor, Command::BitNot,
+    Command::Shl, Command::Shr, Command::ToInt, Command::ToFloat,
+    Command::ListStart, Command::ListEnd, Command::Finally, Command::EndTry, Command::Open
+];
+
+// tags of the commands with operands
+const TAG_PUSHN: u8 = 200;
+const TAG_PUSHS: u8 = 201;
+const TAG_DEFINE: u8 = 202;
+const TAG_NAMED_REFERENCE: u8 = 203;
+const TAG_ABSOLUTE_REFERENCE: u8 = 204;
+const TAG_LAMBDA: u8 = 205;
+const TAG_NATIVE: u8 = 206;
+const TAG_SKIP_IF_ZERO: u8 = 207;
+const TAG_SKIP_IF_NOT_ZERO: u8 = 208;
+const TAG_PUSHI: u8 = 209;
+const TAG_PUSHL: u8 = 210;
+const TAG_PUSHM: u8 = 211;
+const TAG_TRY: u8 = 212;
+const TAG_CATCH: u8 = 213;
+const TAG_PARAMETERS: u8 = 214;
+const TAG_CAPTURE: u8 = 215;
+const TAG_IMPORT: u8 = 216;
+const TAG_EXPORT: u8 = 217;
+
+/// Why a compiled program could not be created or loaded.
+#[derive(Debug)]
+pub enum BytecodeError {
+    /// the script or one of its includes doesn't parse
+    Syntax(SyntaxError),
+    /// the script or one of its includes is larger than allowed
+    Limit(RuntimeError),
+    /// the file was compiled by a different version and has to be compiled again
+    Stale(String),
+    /// the file is not compiled code or was damaged
+    Corrupt(String)
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::Syntax(err) => write!(f, "{}", err),
+            BytecodeError::Limit(err) => write!(f, "{}", err),
+            BytecodeError::Stale(msg) => write!(f, "stale bytecode: {}, compile the script again", msg),
+            BytecodeError::Corrupt(msg) => write!(f, "corrupted bytecode: {}", msg)
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl From<SyntaxError> for BytecodeError {
+    fn from(err: SyntaxError) -> Self {
+        BytecodeError::Syntax(err)
+    }
+}
+
+impl From<Error> for BytecodeError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Syntax(err) => BytecodeError::Syntax(err),
+            Error::Runtime(err) => BytecodeError::Limit(err)
+        }
+    }
+}
+
+/// A fully parsed program that can be saved and run without lexing it again.
+///
+/// It consists of a prelude followed by the script, so the caller can prepare the stack after the
+/// prelude has run, the way the interpreter pushes the command line arguments.
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    program: Vec<Command>,
+    source: Vec<SourceReference>,
+    definitions: HashMap<String, usize>,
+    files: Vec<SourceFile>,
+    includes: Vec<String>,
+    natives: Vec<String>,
+    /// index of the first command of the script
+    entry: usize
+}
+
+impl Bytecode {
+    /// Lex and parse `prelude` and the script `text` into `env` without running them.
+    ///
+    /// Includes of a literal file name (`"std.hr" include`) are resolved right away and stored in
+    /// the bytecode. An include whose name is computed at runtime or whose file can't be read now
+    /// still reads the file when it runs, and fails there if the file is missing. `env` should be
+    /// fresh, the natives registered in it can be used by the script.
+    pub fn compile(env: &mut Environment, prelude: &str, name: &str, text: &str) -> Result<Bytecode, BytecodeError> {
+        let start = env.program.len();
+        load(env, "<prelude>", prelude, start)?;
+        expand_includes(env, start)?;
+
+        let entry = env.program.len();
+        load(env, name, text, entry)?;
+        expand_includes(env, entry)?;
+
+        Ok(Bytecode {
+            program: env.program.clone(),
+            source: env.source.clone(),
+            definitions: env.definitions.clone(),
+            files: env.files.clone(),
+            includes: env.includes.clone(),
+            natives: env.natives.iter().map(|native| native.name.clone()).collect(),
+            entry
+        })
+    }
+
+    /// Replace the program of `env` with this one and run it, `setup` is called between the
+    /// prelude and the script.
+    pub fn run(&self, env: &mut Environment, setup: impl FnOnce(&mut Environment)) -> Result<(), Error> {
+        let natives = self.native_indices(env)?;
+        let mut program = self.program.clone();
+        for command in program.iter_mut() {
+            if let Command::Native(index) = command {
+                *index = natives[*index];
+            }
+        }
+        let script = program.split_off(self.entry);
+        let script_source = self.source[self.entry..].to_vec();
+
+        env.program = program;
+        env.source = self.source[..self.entry].to_vec();
+        env.definitions = self.definitions.clone();
+        env.files = self.files.clone();
+        env.includes = self.includes.clone();
+        env.call_stack.clear();
+        index_locals(env);
+        env.idx = 0;
+        run(env)?;
+
+        setup(env);
+
+        // includes that were only resolved at runtime moved the script, parse it again in that case
+        let entry = env.program.len();
+        if entry == self.entry {
+            env.program.extend(script);
+            env.source.extend(script_source);
+            index_locals(env);
+        } else {
+            let code = Environment::empty(script, script_source);
+            splice(env, entry, code)?;
+        }
+
+        env.unwind(0);
+        env.idx = entry;
+        let res = run(env);
+        env.idx = env.program.len();
+
+        Ok(res?)
+    }
+
+    /// position of each native used by the bytecode in `env`
+    fn native_indices(&self, env: &Environment) -> Result<Vec<usize>, Error> {
+        self.natives.iter()
+            .map(|name| env.natives.iter().position(|native| &native.name == name)
+                .ok_or_else(|| Error::Runtime(env.error(
+                    ErrorKind::UnknownSymbol, format!("the native function `{}` is not registered", name)
+                ))))
+            .collect()
+    }
+
+    /// whether `bytes` look like a compiled program rather than source code
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+
+        write_usize(&mut payload, self.entry);
+        write_usize(&mut payload, self.files.len());
+        for file in &self.files {
+            write_str(&mut payload, &file.name);
+            write_str(&mut payload, &file.text);
+        }
+        write_usize(&mut payload, self.includes.len());
+        for include in &self.includes {
+            write_str(&mut payload, include);
+        }
+        write_usize(&mut payload, self.natives.len());
+        for native in &self.natives {
+            write_str(&mut payload, native);
+        }
+
+        let mut definitions: Vec<(&String, &usize)> = self.definitions.iter().collect();
+        definitions.sort();
+        write_usize(&mut payload, definitions.len());
+        for (name, position) in definitions {
+            write_str(&mut payload, name);
+            write_usize(&mut payload, *position);
+        }
+
+        write_usize(&mut payload, self.program.len());
+        for (command, source) in self.program.iter().zip(&self.source) {
+            write_command(&mut payload, command);
+            write_source(&mut payload, source);
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(&FORMAT_VERSION.to_le_bytes());
+        write_str(&mut bytes, env!("CARGO_PKG_VERSION"));
+        bytes.extend(&checksum(&payload).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bytecode, BytecodeError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(BytecodeError::Corrupt(String::from("not a compiled horrible program")));
+        }
+        let format = u32::from_le_bytes(reader.array()?);
+        if format != FORMAT_VERSION {
+            return Err(BytecodeError::Stale(format!(
+                "the file has format version {}, this interpreter reads version {}", format, FORMAT_VERSION
+            )));
+        }
+        let version = reader.string()?;
+        if version != env!("CARGO_PKG_VERSION") {
+            return Err(BytecodeError::Stale(format!(
+                "the file was compiled by version {}, this is version {}", version, env!("CARGO_PKG_VERSION")
+            )));
+        }
+        let sum = u64::from_le_bytes(reader.array()?);
+        if checksum(&bytes[reader.position..]) != sum {
+            return Err(BytecodeError::Corrupt(String::from("checksum mismatch")));
+        }
+
+        let entry = reader.usize()?;
+        let mut files = Vec::new();
+        for _ in 0..reader.usize()? {
+            files.push(SourceFile { name: reader.string()?, text: reader.string()? });
+        }
+        let mut includes = Vec::new();
+        for _ in 0..reader.usize()? {
+            includes.push(reader.string()?);
+        }
+        let mut natives = Vec::new();
+        for _ in 0..reader.usize()? {
+            natives.push(reader.string()?);
+        }
+        let mut definitions = HashMap::new();
+        for _ in 0..reader.usize()? {
+            definitions.insert(reader.string()?, reader.usize()?);
+        }
+        let mut program = Vec::new();
+        let mut source = Vec::new();
+        for _ in 0..reader.usize()? {
+            program.push(reader.command(natives.len())?);
+            source.push(reader.source(files.len())?);
+        }
+
+        if reader.position != bytes.len() {
+            return Err(BytecodeError::Corrupt(String::from("unexpected data after the program")));
+        }
+        if entry > program.len() || definitions.values().any(|&position| position >= program.len()) {
+            return Err(BytecodeError::Corrupt(String::from("position outside of the program")));
+        }
+        check_offsets(&program)?;
+
+        Ok(Bytecode { program, source, definitions, files, includes, natives, entry })
+    }
+}
+
+/// check that the jumps stored in the commands land inside of `program`, the interpreter relies on it
+fn check_offsets(program: &[Command]) -> Result<(), BytecodeError> {
+    let inside = |position: usize, offset: usize| position.checked_add(offset).is_some_and(|target| target < program.len());
+
+    for (position, command) in program.iter().enumerate() {
+        let valid = match command {
+            // the name of a definition is the command in front of it
+            Command::Define(_, skip) => position > 0 && inside(position, *skip),
+            Command::Lambda(skip) | Command::Catch(skip) => inside(position, *skip),
+            Command::Try(catch, finally, end) => [catch, finally, end].iter().all(|&&offset| inside(position, offset)),
+            Command::SkipIfZero(skip) | Command::SkipIfNotZero(skip) => position.checked_add(*skip).is_some_and(|target| target <= program.len()),
+            Command::AbsoluteReference(target) => *target < program.len(),
+            Command::Capture(_) => matches!(program.get(position + 1), Some(Command::Lambda(_))),
+            _ => true
+        };
+        if !valid {
+            return Err(BytecodeError::Corrupt(format!("command {} jumps outside of the program", position)));
+        }
+    }
+    Ok(())
+}
+
+/// resolve every `"name" include` and `"name" import` after `start` and insert the included code behind it
+fn expand_includes(env: &mut Environment, start: usize) -> Result<(), BytecodeError> {
+    let mut idx = usize::max(start, 1);
+
+    while idx < env.program.len() {
+        if let (Command::Pushs(filename), Command::Include | Command::Import(_)) = (&env.program[idx - 1], &env.program[idx]) {
+            // a file that can't be read is left to the `include` at runtime, which only fails if
+            // it is reached
+            if let Ok(found) = find_include(env, idx, filename) {
+                if env.includes.contains(&found.key) {
+                    env.program[idx - 1] = Command::Pushs(found.key);
+                } else if let Ok(content) = found.read() {
+                    // name the file by its key, so running the bytecode doesn't search for it again
+                    env.program[idx - 1] = Command::Pushs(found.key.clone());
+                    env.includes.push(found.key);
+                    load(env, &found.name, &content, idx + 1)?;
+                }
+            }
+        }
+        idx += 1;
+    }
+    Ok(())
+}
+
+/// FNV-1a, only meant to detect damaged files
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+fn write_usize(out: &mut Vec<u8>, n: usize) {
+    out.extend(&(n as u64).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_usize(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+fn write_command(out: &mut Vec<u8>, command: &Command) {
+    if let Some(tag) = SIMPLE_COMMANDS.iter().position(|c| c == command) {
+        out.push(tag as u8);
+        return;
+    }
+
+    match command {
+        Command::Pushn(n) => {
+            out.push(TAG_PUSHN);
+            out.extend(&n.to_le_bytes());
+        },
+        Command::Pushi(n) => {
+            out.push(TAG_PUSHI);
+            out.extend(&n.to_le_bytes());
+        },
+        Command::Pushl(items) => {
+            out.push(TAG_PUSHL);
+            write_usize(out, items.len());
+            for item in items {
+                write_command(out, &item.clone().into_cell());
+            }
+        },
+        Command::Pushm(entries) => {
+            out.push(TAG_PUSHM);
+            write_usize(out, entries.len());
+            for (key, value) in entries {
+                write_command(out, &key.to_slot().into_cell());
+                write_command(out, &value.clone().into_cell());
+            }
+        },
+        Command::Pushs(s) => {
+            out.push(TAG_PUSHS);
+            write_str(out, s);
+        },
+        Command::Define(visibility, skip) => {
+            out.push(TAG_DEFINE);
+            out.push(if *visibility == Visibility::Public { 0 } else { 1 });
+            write_usize(out, *skip);
+        },
+        Command::NamedReference(name, offset) => {
+            out.push(TAG_NAMED_REFERENCE);
+            write_str(out, name);
+            write_usize(out, *offset);
+        },
+        Command::AbsoluteReference(position) => {
+            out.push(TAG_ABSOLUTE_REFERENCE);
+            write_usize(out, *position);
+        },
+        Command::Lambda(skip) => {
+            out.push(TAG_LAMBDA);
+            write_usize(out, *skip);
+        },
+        Command::Native(index) => {
+            out.push(TAG_NATIVE);
+            write_usize(out, *index);
+        },
+        Command::SkipIfZero(skip) => {
+            out.push(TAG_SKIP_IF_ZERO);
+            write_usize(out, *skip);
+        },
+        Command::SkipIfNotZero(skip) => {
+            out.push(TAG_SKIP_IF_NOT_ZERO);
+            write_usize(out, *skip);
+        },
+        Command::Try(catch, finally, end) => {
+            out.push(TAG_TRY);
+            write_usize(out, *catch);
+            write_usize(out, *finally);
+            write_usize(out, *end);
+        },
+        Command::Catch(skip) => {
+            out.push(TAG_CATCH);
+            write_usize(out, *skip);
+        },
+        Command::Parameters(names) => {
+            out.push(TAG_PARAMETERS);
+            write_usize(out, names.len());
+            for name in names {
+                write_str(out, name);
+            }
+        },
+        Command::Capture(names) => {
+            out.push(TAG_CAPTURE);
+            write_usize(out, names.len());
+            for name in names {
+                write_str(out, name);
+            }
+        },
+        Command::Import(alias) => {
+            out.push(TAG_IMPORT);
+            write_str(out, alias);
+        },
+        Command::Export(names) => {
+            out.push(TAG_EXPORT);
+            write_usize(out, names.len());
+            for name in names {
+                write_str(out, name);
+            }
+        },
+        Command::HeapReference(_, _) => unreachable!("heap references only exist while a program runs"),
+        Command::Closure(_, _) => unreachable!("closures only exist while a program runs"),
+        other => unreachable!("{:?} is missing from SIMPLE_COMMANDS", other)
+    }
+}
+
+fn write_source(out: &mut Vec<u8>, source: &SourceReference) {
+    match source {
+        SourceReference::Visible(text, _) => {
+            out.push(0);
+            write_str(out, text);
+        },
+        SourceReference::Invisible(_) => out.push(1)
+    }
+
+    let span = source.span();
+    for n in &[span.file, span.line, span.column, span.length] {
+        write_usize(out, *n);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self.position.checked_add(n).filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| BytecodeError::Corrupt(String::from("unexpected end of file")))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        Ok(<[u8; N]>::try_from(self.take(N)?).unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        usize::try_from(u64::from_le_bytes(self.array()?))
+            .map_err(|_| BytecodeError::Corrupt(String::from("number out of range")))
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| BytecodeError::Corrupt(String::from("invalid utf-8 in string")))
+    }
+
+    fn command(&mut self, natives: usize) -> Result<Command, BytecodeError> {
+        let tag = self.u8()?;
+        if let Some(command) = SIMPLE_COMMANDS.get(tag as usize) {
+            return Ok(command.clone());
+        }
+
+        Ok(match tag {
+            TAG_PUSHN => Command::Pushn(f64::from_le_bytes(self.array()?)),
+            TAG_PUSHI => Command::Pushi(i64::from_le_bytes(self.array()?)),
+            TAG_PUSHS => Command::Pushs(self.string()?),
+            TAG_PUSHL => {
+                let len = self.usize()?;
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    let item = self.command(natives)?.value()
+                        .ok_or_else(|| BytecodeError::Corrupt(String::from("a list item is not a value")))?;
+                    items.push(item);
+                }
+                Command::Pushl(items)
+            },
+            TAG_PUSHM => {
+                let len = self.usize()?;
+                let mut entries = BTreeMap::new();
+                for _ in 0..len {
+                    let key = self.command(natives)?.value().as_ref().and_then(MapKey::from_slot)
+                        .ok_or_else(|| BytecodeError::Corrupt(String::from("invalid map key")))?;
+                    let value = self.command(natives)?.value()
+                        .ok_or_else(|| BytecodeError::Corrupt(String::from("a map value is not a value")))?;
+                    entries.insert(key, value);
+                }
+                Command::Pushm(entries)
+            },
+            TAG_DEFINE => {
+                let visibility = match self.u8()? {
+                    0 => Visibility::Public,
+                    1 => Visibility::Private,
+                    other => return Err(BytecodeError::Corrupt(format!("invalid visibility {}", other)))
+                };
+                Command::Define(visibility, self.usize()?)
+            },
+            TAG_NAMED_REFERENCE => Command::NamedReference(self.string()?, self.usize()?),
+            TAG_ABSOLUTE_REFERENCE => Command::AbsoluteReference(self.usize()?),
+            TAG_LAMBDA => Command::Lambda(self.usize()?),
+            TAG_SKIP_IF_ZERO => Command::SkipIfZero(self.usize()?),
+            TAG_SKIP_IF_NOT_ZERO => Command::SkipIfNotZero(self.usize()?),
+            TAG_TRY => Command::Try(self.usize()?, self.usize()?, self.usize()?),
+            TAG_CATCH => Command::Catch(self.usize()?),
+            TAG_CAPTURE => {
+                let count = self.usize()?;
+                Command::Capture((0..count).map(|_| self.string()).collect::<Result<_, _>>()?)
+            },
+            TAG_PARAMETERS => {
+                let count = self.usize()?;
+                Command::Parameters((0..count).map(|_| self.string()).collect::<Result<_, _>>()?)
+            },
+            TAG_IMPORT => Command::Import(self.string()?),
+            TAG_EXPORT => {
+                let count = self.usize()?;
+                Command::Export((0..count).map(|_| self.string()).collect::<Result<_, _>>()?)
+            },
+            TAG_NATIVE => match self.usize()? {
+                index if index < natives => Command::Native(index),
+                index => return Err(BytecodeError::Corrupt(format!("unknown native function {}", index)))
+            },
+            other => return Err(BytecodeError::Corrupt(format!("unknown command {}", other)))
+        })
+    }
+
+    fn source(&mut self, files: usize) -> Result<SourceReference, BytecodeError> {
+        let text = match self.u8()? {
+            0 => Some(self.string()?),
+            1 => None,
+            other => return Err(BytecodeError::Corrupt(format!("invalid source reference {}", other)))
+        };
+        let span = Span { file: self.usize()?, line: self.usize()?, column: self.usize()?, length: self.usize()? };
+        if span.file >= files || span.line == 0 || span.column == 0 {
+            return Err(BytecodeError::Corrupt(String::from("invalid source position")));
+        }
+
+        Ok(match text {
+            Some(text) => SourceReference::Visible(text, span),
+            None => SourceReference::Invisible(span)
+        })
+    }
+}
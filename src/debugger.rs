@@ -0,0 +1,342 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::{Command, Environment, ErrorKind, RuntimeError};
+
+
+const HELP: &str = "\
+step, s              run the next instruction
+next, n              run the next instruction, calls are run as a whole
+out, o               run until the current definition returns
+continue, c          run until the next breakpoint
+break, b <where>     stop at a definition (`@name`) or a line (`file:line`)
+watch, w <@name>     stop after the cell of a definition was written, `@name+1` for later cells
+delete, d <n>        remove breakpoint or watchpoint n
+info, i              list breakpoints and watchpoints
+stack                print the data stack
+backtrace, bt        print the call stack
+print, p <@name>     print the value in the cell of a definition
+list, l              show the current instruction
+quit, q              stop the program
+an empty line repeats the previous command";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Point {
+    /// first instruction of a definition
+    Definition(String),
+    /// file name (or the end of its path) and line
+    Line(String, usize),
+    /// cell `offset` of a definition was written
+    Watch(String, usize)
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Point::Definition(name) => write!(f, "breakpoint at @{}", name),
+            Point::Line(file, line) => write!(f, "breakpoint at {}:{}", file, line),
+            Point::Watch(name, 0) => write!(f, "watchpoint on @{}", name),
+            Point::Watch(name, offset) => write!(f, "watchpoint on @{}+{}", name, offset)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Continue,
+    Step,
+    /// stop once the call stack is at most this deep again
+    StepOver(usize),
+    /// stop once the call stack is less deep than this
+    StepOut(usize)
+}
+
+enum Action {
+    Resume,
+    Stay,
+    Quit
+}
+
+/// Interactive debugger that stops a running program before chosen instructions.
+///
+/// Attach it with `Environment::set_debugger`. It starts out stopping at the first instruction, a
+/// program also stops at breakpoints, at the `breakpoint` keyword and after a watched cell changed.
+pub struct Debugger {
+    points: Vec<(usize, Point)>,
+    next_id: usize,
+    mode: Mode,
+    /// file and line of the previous instruction, line breakpoints only trigger when entering a line
+    last_line: Option<(usize, usize)>,
+    /// a watchpoint that triggered, reported before the next instruction
+    pending: Option<String>,
+    last_command: String,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Debugger")
+            .field("points", &self.points)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::with_io(Box::new(BufReader::new(io::stdin())), Box::new(io::stdout()))
+    }
+}
+
+impl Debugger {
+    /// a debugger on the terminal
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    /// a debugger that reads its commands from `input` and reports to `output`
+    pub fn with_io(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Debugger {
+            points: Vec::new(),
+            next_id: 1,
+            mode: Mode::Step,
+            last_line: None,
+            pending: None,
+            last_command: String::new(),
+            input,
+            output
+        }
+    }
+
+    /// Stop at a definition (`@name`) or a line (`file:line`), returns the number of the breakpoint.
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let point = match location.rsplit_once(':') {
+            Some((file, line)) if !file.is_empty() && !file.ends_with(':') && line.parse::<usize>().is_ok() =>
+                Point::Line(file.into(), line.parse().unwrap()),
+            _ if location.trim_start_matches('@').is_empty() => return Err(String::from("breakpoint needs a location")),
+            _ => Point::Definition(location.trim_start_matches('@').into())
+        };
+        Ok(self.add_point(point))
+    }
+
+    /// Stop after a `put` or `->` wrote the cell of a definition (`@name` or `@name+offset`),
+    /// returns the number of the watchpoint.
+    pub fn add_watchpoint(&mut self, cell: &str) -> Result<usize, String> {
+        let cell = cell.trim_start_matches('@');
+        let (name, offset) = match cell.rsplit_once('+') {
+            Some((name, offset)) => (name, offset.parse().map_err(|_| format!("invalid offset `{}`", offset))?),
+            None => (cell, 0)
+        };
+        if name.is_empty() {
+            return Err(String::from("watchpoint needs a definition"));
+        }
+        Ok(self.add_point(Point::Watch(name.into(), offset)))
+    }
+
+    fn add_point(&mut self, point: Point) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push((id, point));
+        id
+    }
+
+    /// called by `run` before the instruction at `env.idx` is executed
+    pub(crate) fn before_instruction(&mut self, env: &Environment) -> Result<(), RuntimeError> {
+        let line = env.source.get(env.idx).map(|s| (s.span().file, s.span().line));
+        let entered_line = line != self.last_line;
+        self.last_line = line;
+
+        let depth = env.call_stack.len();
+        let reason = if let Some(watch) = self.pending.take() {
+            Some(watch)
+        } else if env.program.get(env.idx) == Some(&Command::Breakpoint) {
+            Some(String::from("`breakpoint`"))
+        } else if let Some((id, point)) = self.points.iter().find(|(_, point)| hits(point, env, entered_line)) {
+            Some(format!("{} {}", id, point))
+        } else {
+            match self.mode {
+                Mode::Step => Some(String::from("step")),
+                Mode::StepOver(d) if depth <= d => Some(String::from("step")),
+                Mode::StepOut(d) if depth < d => Some(String::from("returned")),
+                _ => None
+            }
+        };
+
+        match reason {
+            Some(reason) => self.pause(env, &reason),
+            None => Ok(())
+        }
+    }
+
+    /// called after a `put` or `->` wrote the cell at `position`
+    pub(crate) fn written(&mut self, env: &Environment, position: usize) {
+        for (id, point) in &self.points {
+            if let Point::Watch(name, offset) = point {
+                if env.definitions.get(name).map(|p| p + 1 + offset) == Some(position) {
+                    let value = cell_text(&env.program[position]);
+                    self.pending = Some(format!("{} {} changed to {}", id, point, value));
+                }
+            }
+        }
+    }
+
+    fn pause(&mut self, env: &Environment, reason: &str) -> Result<(), RuntimeError> {
+        self.mode = Mode::Continue;
+        let name = env.call_stack.last().map_or(String::from("<toplevel>"), |frame| env.name_of(frame.target));
+        self.say(&format!("stopped in {} ({})", name, reason));
+        self.list(env);
+
+        loop {
+            write!(self.output, "(hrdb) ").ok();
+            self.output.flush().ok();
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                // without input there is nobody to debug, let the program finish
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(_) => {}
+            }
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string()
+            };
+            self.last_command = line.clone();
+
+            match self.command(env, &line) {
+                Action::Resume => return Ok(()),
+                Action::Stay => {},
+                Action::Quit => return Err(env.error(ErrorKind::Interrupted, "stopped by the debugger"))
+            }
+        }
+    }
+
+    fn command(&mut self, env: &Environment, line: &str) -> Action {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, "")
+        };
+        let depth = env.call_stack.len();
+
+        match command {
+            "step" | "s" => self.mode = Mode::Step,
+            "next" | "n" => self.mode = Mode::StepOver(depth),
+            "out" | "o" if depth == 0 => {
+                self.say("already on the toplevel");
+                return Action::Stay;
+            },
+            "out" | "o" => self.mode = Mode::StepOut(depth),
+            "continue" | "c" => self.mode = Mode::Continue,
+            "quit" | "q" => return Action::Quit,
+            "break" | "b" => {
+                let message = match self.add_breakpoint(argument) {
+                    Ok(id) => format!("{} {}", id, self.points.last().unwrap().1),
+                    Err(err) => err
+                };
+                self.say(&message);
+                return Action::Stay;
+            },
+            "watch" | "w" => {
+                let message = match self.add_watchpoint(argument) {
+                    Ok(id) => format!("{} {}", id, self.points.last().unwrap().1),
+                    Err(err) => err
+                };
+                self.say(&message);
+                return Action::Stay;
+            },
+            "delete" | "d" => {
+                let before = self.points.len();
+                self.points.retain(|(id, _)| argument.parse() != Ok(*id));
+                if self.points.len() == before {
+                    self.say(&format!("no breakpoint or watchpoint `{}`", argument));
+                }
+                return Action::Stay;
+            },
+            "info" | "i" => {
+                let text = self.points.iter().map(|(id, point)| format!("{} {}", id, point)).collect::<Vec<_>>();
+                self.say(&if text.is_empty() { String::from("no breakpoints or watchpoints") } else { text.join("\n") });
+                return Action::Stay;
+            },
+            "stack" => {
+                let stack = env.stack.stack.iter().map(|slot| format!("{:?}", slot)).collect::<Vec<_>>().join("\n");
+                self.say(&if stack.is_empty() { String::from("the stack is empty") } else { stack });
+                return Action::Stay;
+            },
+            "backtrace" | "bt" => {
+                self.backtrace(env);
+                return Action::Stay;
+            },
+            "print" | "p" => {
+                self.print_cell(env, argument);
+                return Action::Stay;
+            },
+            "list" | "l" => {
+                self.list(env);
+                return Action::Stay;
+            },
+            "help" | "h" => {
+                self.say(HELP);
+                return Action::Stay;
+            },
+            other => {
+                self.say(&format!("unknown command `{}`, try `help`", other));
+                return Action::Stay;
+            }
+        }
+        Action::Resume
+    }
+
+    fn list(&mut self, env: &Environment) {
+        let command = env.program.get(env.idx).map_or(String::new(), |c| format!("{:?}", c));
+        match env.location(env.idx) {
+            Some(location) => self.say(&format!("  --> {} {}\n{}", location, command, location.snippet())),
+            None => self.say(&format!("instruction {} {}", env.idx, command))
+        }
+    }
+
+    fn backtrace(&mut self, env: &Environment) {
+        let mut text = vec![format!("#0 {}", env.location(env.idx).map_or(String::from("?"), |l| l.to_string()))];
+        for (i, frame) in env.call_stack.iter().rev().enumerate() {
+            let from = env.location(frame.caller).map_or(format!("instruction {}", frame.caller), |l| l.to_string());
+            text.push(format!("#{} {} (called from {})", i + 1, env.name_of(frame.target), from));
+        }
+        self.say(&text.join("\n"));
+    }
+
+    fn print_cell(&mut self, env: &Environment, cell: &str) {
+        let cell = cell.trim_start_matches('@');
+        let (name, offset) = match cell.rsplit_once('+').map(|(name, offset)| (name, offset.parse::<usize>())) {
+            Some((name, Ok(offset))) => (name, offset),
+            _ => (cell, 0)
+        };
+
+        let message = match env.definitions.get(name).and_then(|p| env.program.get(p + 1 + offset)) {
+            Some(command) => format!("@{} = {}", cell, cell_text(command)),
+            None => format!("no such symbol: `{}`", name)
+        };
+        self.say(&message);
+    }
+
+    fn say(&mut self, text: &str) {
+        writeln!(self.output, "{}", text).ok();
+    }
+}
+
+fn hits(point: &Point, env: &Environment, entered_line: bool) -> bool {
+    match point {
+        Point::Definition(name) => env.definitions.get(name).map(|p| p + 1) == Some(env.idx),
+        Point::Line(file, line) => entered_line && env.location(env.idx).is_some_and(|location| {
+            location.line == *line && (location.file == *file || location.file.ends_with(&format!("/{}", file)))
+        }),
+        Point::Watch(_, _) => false
+    }
+}
+
+/// a cell as the debugger shows it, values like `print` would and code as the command
+fn cell_text(command: &Command) -> String {
+    match command.value() {
+        Some(value) => format!("{:?}", value),
+        None => format!("<code: {:?}>", command)
+    }
+}
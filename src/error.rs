@@ -0,0 +1,338 @@
+use std::fmt;
+
+use crate::{Environment, StackSlot};
+
+
+/// Category of a `RuntimeError`, each kind has a stable code that tooling can match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// a command needed more values than the stack holds
+    StackUnderflow,
+    /// a value on the stack has the wrong type for the command
+    TypeMismatch,
+    /// a name is not defined
+    UnknownSymbol,
+    /// a reference does not point to something usable
+    BadReference,
+    /// an included file could not be loaded
+    IncludeFailed,
+    /// the command can't be used in this situation
+    InvalidOperation,
+    /// code loaded at runtime could not be parsed
+    Syntax,
+    /// an `assert` or `assert-eq` did not hold
+    AssertionFailed,
+    /// the program was stopped from the debugger
+    Interrupted,
+    /// the program used more than `Environment::set_limits` allows
+    LimitExceeded,
+    /// integer overflow, division by zero or a similar impossible calculation
+    Arithmetic,
+    /// an index outside of a list
+    IndexOutOfRange,
+    /// a key that is not in a map
+    KeyNotFound,
+    /// a value can't be converted, like text that is not a number or bytes that are not UTF-8
+    Conversion,
+    /// a regular expression doesn't compile
+    InvalidPattern,
+    /// the operating system reported an error while accessing a file
+    Io,
+    /// raised by the script with `throw`
+    Thrown
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::StackUnderflow => "E0001",
+            ErrorKind::TypeMismatch => "E0002",
+            ErrorKind::UnknownSymbol => "E0003",
+            ErrorKind::BadReference => "E0004",
+            ErrorKind::IncludeFailed => "E0005",
+            ErrorKind::InvalidOperation => "E0006",
+            ErrorKind::Syntax => "E0007",
+            ErrorKind::AssertionFailed => "E0008",
+            ErrorKind::Interrupted => "E0009",
+            ErrorKind::LimitExceeded => "E0010",
+            ErrorKind::Arithmetic => "E0011",
+            ErrorKind::IndexOutOfRange => "E0012",
+            ErrorKind::KeyNotFound => "E0013",
+            ErrorKind::Conversion => "E0014",
+            ErrorKind::InvalidPattern => "E0015",
+            ErrorKind::Io => "E0016",
+            ErrorKind::Thrown => "E0017"
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::StackUnderflow => "stack-underflow",
+            ErrorKind::TypeMismatch => "type-mismatch",
+            ErrorKind::UnknownSymbol => "unknown-symbol",
+            ErrorKind::BadReference => "bad-reference",
+            ErrorKind::IncludeFailed => "include-failed",
+            ErrorKind::InvalidOperation => "invalid-operation",
+            ErrorKind::Syntax => "syntax-error",
+            ErrorKind::AssertionFailed => "assertion-failed",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::LimitExceeded => "limit-exceeded",
+            ErrorKind::Arithmetic => "arithmetic-error",
+            ErrorKind::IndexOutOfRange => "index-out-of-range",
+            ErrorKind::KeyNotFound => "key-not-found",
+            ErrorKind::Conversion => "conversion-error",
+            ErrorKind::InvalidPattern => "invalid-pattern",
+            ErrorKind::Io => "io-error",
+            ErrorKind::Thrown => "thrown"
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A resolved source position, including the text of the line for diagnostics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub text: String
+}
+
+impl Location {
+    /// the source line with a caret under the token
+    pub fn snippet(&self) -> String {
+        let number = self.line.to_string();
+        let padding = " ".repeat(number.len());
+        let indent: String = self.text.chars().take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let visible = self.text.chars().count().saturating_sub(self.column - 1);
+        let carets = "^".repeat(self.length.clamp(1, usize::max(visible, 1)));
+
+        format!("{} |\n{} | {}\n{} | {}{}", padding, number, self.text, padding, indent, carets)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// One entry of the call stack at the time of an error.
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    /// index of the instruction that made the call
+    pub caller: usize,
+    /// index the call jumped to
+    pub target: usize,
+    /// name of the called definition
+    pub name: String,
+    /// source position of the call
+    pub location: Option<Location>
+}
+
+#[derive(Debug, Clone)]
+struct Details {
+    kind: ErrorKind,
+    msg: String,
+    instruction: usize,
+    token: String,
+    location: Option<Location>,
+    operands: Vec<StackSlot>,
+    stack: Vec<StackSlot>,
+    trace: Vec<TraceFrame>
+}
+
+/// An error raised while running a program.
+///
+/// Only a snapshot of the interesting parts of the environment is kept, the environment itself
+/// stays usable after the error.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    details: Box<Details>
+}
+
+impl RuntimeError {
+    pub(crate) fn new(kind: ErrorKind, msg: impl Into<String>, env: &Environment) -> Self {
+        let trace = env.call_stack.iter()
+            .map(|frame| TraceFrame {
+                caller: frame.caller,
+                target: frame.target,
+                name: env.name_of(frame.target),
+                location: env.location(frame.caller)
+            })
+            .collect();
+
+        RuntimeError {
+            details: Box::new(Details {
+                kind,
+                msg: msg.into(),
+                instruction: env.idx,
+                token: env.source.get(env.idx).map(|s| s.to_string()).unwrap_or_default(),
+                location: env.location(env.idx),
+                operands: Vec::new(),
+                stack: env.stack.stack.clone(),
+                trace
+            })
+        }
+    }
+
+    /// attach the values the failing command was working on
+    pub(crate) fn with_operands(mut self, operands: Vec<StackSlot>) -> Self {
+        self.details.operands = operands;
+        self
+    }
+
+    /// point the error at `token` in the source instead of at the current instruction
+    pub(crate) fn at(mut self, token: &str, location: Option<Location>) -> Self {
+        self.details.token = token.into();
+        self.details.location = location;
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.details.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.details.kind.code()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.details.msg
+    }
+
+    /// index of the failing instruction in the program
+    pub fn instruction(&self) -> usize {
+        self.details.instruction
+    }
+
+    /// source token of the failing instruction
+    pub fn token(&self) -> &str {
+        &self.details.token
+    }
+
+    /// file, line and column of the failing instruction
+    pub fn location(&self) -> Option<&Location> {
+        self.details.location.as_ref()
+    }
+
+    /// values the failing command popped from the stack, if any
+    pub fn operands(&self) -> &[StackSlot] {
+        &self.details.operands
+    }
+
+    /// the data stack at the time of the error
+    pub fn stack(&self) -> &[StackSlot] {
+        &self.details.stack
+    }
+
+    pub fn trace(&self) -> &[TraceFrame] {
+        &self.details.trace
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.details;
+
+        writeln!(f, "RuntimeError {} ({}) at `{}`: {}", d.kind.code(), d.kind, d.token, d.msg)?;
+        if let Some(location) = &d.location {
+            writeln!(f, "  --> {}", location)?;
+            writeln!(f, "{}", location.snippet())?;
+        }
+        if !d.operands.is_empty() {
+            writeln!(f, "operands: {:?}", d.operands)?;
+        }
+        write!(f, "stack: {:?}", d.stack)?;
+        if !d.trace.is_empty() {
+            write!(f, "\ncallstack:")?;
+        }
+        for frame in d.trace.iter().rev() {
+            match &frame.location {
+                Some(location) => write!(f, "\n    {} (called from {})", frame.name, location)?,
+                None => write!(f, "\n    {} (called from instruction {})", frame.name, frame.caller)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// An error found while lexing or parsing source code, before anything is run.
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    msg: String,
+    location: Option<Location>
+}
+
+impl SyntaxError {
+    pub(crate) fn new(msg: impl Into<String>, location: Option<Location>) -> Self {
+        SyntaxError { msg: msg.into(), location }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SyntaxError: {}", self.msg)?;
+        if let Some(location) = &self.location {
+            write!(f, "\n  --> {}\n{}", location, location.snippet())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Anything that can go wrong when running source code.
+#[derive(Debug)]
+pub enum Error {
+    Syntax(SyntaxError),
+    Runtime(RuntimeError)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax(err) => write!(f, "{}", err),
+            Error::Runtime(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Syntax(err) => Some(err),
+            Error::Runtime(err) => Some(err)
+        }
+    }
+}
+
+impl From<SyntaxError> for Error {
+    fn from(err: SyntaxError) -> Self {
+        Error::Syntax(err)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
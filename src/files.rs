@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::builtins::pop_string;
+use crate::{Environment, ErrorKind, RuntimeError, StackSlot};
+
+
+enum Handle {
+    Reader(BufReader<File>),
+    Writer(BufWriter<File>)
+}
+
+/// Files opened with `file-open`, a script refers to them by number.
+#[derive(Default)]
+pub(crate) struct Handles {
+    next: i64,
+    open: HashMap<i64, Handle>
+}
+
+impl fmt::Debug for Handles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut open: Vec<&i64> = self.open.keys().collect();
+        open.sort();
+        f.debug_struct("Handles").field("open", &open).finish()
+    }
+}
+
+pub(crate) fn register(env: &mut Environment) {
+    env.register_native("read-file", read_file);
+    env.register_native("read-lines", read_lines);
+    env.register_native("write-file", write_file);
+    env.register_native("append-file", append_file);
+    env.register_native("file-open", file_open);
+    env.register_native("file-read-line", file_read_line);
+    env.register_native("file-write", file_write);
+    env.register_native("file-close", file_close);
+    env.register_native("file-exists", file_exists);
+    env.register_native("remove-file", remove_file);
+    env.register_native("rename-file", rename_file);
+    env.register_native("list-dir", list_dir);
+    env.register_native("path-join", path_join);
+    env.register_native("basename", basename);
+}
+
+fn pop_handle(env: &mut Environment, name: &str) -> Result<i64, RuntimeError> {
+    match env.stack.pop() {
+        Some(StackSlot::Int(handle)) if env.handles.borrow().open.contains_key(&handle) => Ok(handle),
+        Some(other) => Err(env.error(ErrorKind::BadReference, format!("{} needs an open file", name))
+            .with_operands(vec![other])),
+        None => Err(env.error(ErrorKind::StackUnderflow, format!("{} needs a file", name)))
+    }
+}
+
+/// turn an OS error into a runtime error that mentions what was tried
+fn io_error(env: &Environment, action: &str, path: &str, err: io::Error) -> RuntimeError {
+    env.error(ErrorKind::Io, format!("unable to {} `{}`: {}", action, path, err))
+}
+
+/// ( path -- string )
+fn read_file(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "read-file")?;
+    let text = fs::read_to_string(&path).map_err(|err| io_error(env, "read", &path, err))?;
+    env.stack.push(StackSlot::String(text));
+    Ok(())
+}
+
+/// ( path -- list ) the lines without their line endings
+fn read_lines(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "read-lines")?;
+    let text = fs::read_to_string(&path).map_err(|err| io_error(env, "read", &path, err))?;
+    env.stack.push(StackSlot::List(text.lines().map(|line| StackSlot::String(line.into())).collect()));
+    Ok(())
+}
+
+/// ( text path -- ) replaces the file if it exists
+fn write_file(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "write-file")?;
+    let text = pop_string(env, "write-file")?;
+    fs::write(&path, text).map_err(|err| io_error(env, "write", &path, err))
+}
+
+/// ( text path -- ) creates the file if it doesn't exist
+fn append_file(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "append-file")?;
+    let text = pop_string(env, "append-file")?;
+    OpenOptions::new().append(true).create(true).open(&path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|err| io_error(env, "append to", &path, err))
+}
+
+/// ( path mode -- handle ) the mode is `r` to read, `w` to write or `a` to append
+fn file_open(env: &mut Environment) -> Result<(), RuntimeError> {
+    let mode = pop_string(env, "file-open")?;
+    let path = pop_string(env, "file-open")?;
+
+    let handle = match mode.as_str() {
+        "r" => File::open(&path).map(|file| Handle::Reader(BufReader::new(file))),
+        "w" => File::create(&path).map(|file| Handle::Writer(BufWriter::new(file))),
+        "a" => OpenOptions::new().append(true).create(true).open(&path).map(|file| Handle::Writer(BufWriter::new(file))),
+        _ => return Err(env.error(ErrorKind::InvalidOperation, format!("unknown file mode `{}`, use r, w or a", mode)))
+    }.map_err(|err| io_error(env, "open", &path, err))?;
+
+    let mut handles = env.handles.borrow_mut();
+    handles.next += 1;
+    let id = handles.next;
+    handles.open.insert(id, handle);
+    drop(handles);
+
+    env.stack.push(StackSlot::Int(id));
+    Ok(())
+}
+
+/// ( handle -- line ) the next line without its line ending, -1 at the end of the file
+fn file_read_line(env: &mut Environment) -> Result<(), RuntimeError> {
+    let id = pop_handle(env, "file-read-line")?;
+
+    let mut line = String::new();
+    let read = match env.handles.borrow_mut().open.get_mut(&id) {
+        Some(Handle::Reader(reader)) => reader.read_line(&mut line),
+        _ => return Err(env.error(ErrorKind::InvalidOperation, "the file was not opened for reading"))
+    };
+
+    match read {
+        Ok(0) => env.stack.push(StackSlot::Int(-1)),
+        Ok(_) => {
+            let end = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(end);
+            env.stack.push(StackSlot::String(line));
+        },
+        Err(err) => return Err(env.error(ErrorKind::Io, format!("unable to read from file {}: {}", id, err)))
+    }
+    Ok(())
+}
+
+/// ( text handle -- )
+fn file_write(env: &mut Environment) -> Result<(), RuntimeError> {
+    let id = pop_handle(env, "file-write")?;
+    let text = pop_string(env, "file-write")?;
+
+    let written = match env.handles.borrow_mut().open.get_mut(&id) {
+        Some(Handle::Writer(writer)) => writer.write_all(text.as_bytes()),
+        _ => return Err(env.error(ErrorKind::InvalidOperation, "the file was not opened for writing"))
+    };
+    written.map_err(|err| env.error(ErrorKind::Io, format!("unable to write to file {}: {}", id, err)))
+}
+
+/// ( handle -- ) writes what is still buffered
+fn file_close(env: &mut Environment) -> Result<(), RuntimeError> {
+    let id = pop_handle(env, "file-close")?;
+
+    let handle = env.handles.borrow_mut().open.remove(&id);
+    if let Some(Handle::Writer(mut writer)) = handle {
+        writer.flush().map_err(|err| env.error(ErrorKind::Io, format!("unable to write to file {}: {}", id, err)))?;
+    }
+    Ok(())
+}
+
+/// ( path -- flag ) 1 for files and directories
+fn file_exists(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "file-exists")?;
+    env.stack.push(StackSlot::Int(Path::new(&path).exists().into()));
+    Ok(())
+}
+
+/// ( path -- )
+fn remove_file(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "remove-file")?;
+    fs::remove_file(&path).map_err(|err| io_error(env, "remove", &path, err))
+}
+
+/// ( from to -- )
+fn rename_file(env: &mut Environment) -> Result<(), RuntimeError> {
+    let to = pop_string(env, "rename-file")?;
+    let from = pop_string(env, "rename-file")?;
+    fs::rename(&from, &to).map_err(|err| io_error(env, "rename", &from, err))
+}
+
+/// ( path -- list ) the sorted names of the entries in a directory
+fn list_dir(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "list-dir")?;
+
+    let mut names = fs::read_dir(&path)
+        .and_then(|entries| entries.map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned())).collect::<io::Result<Vec<String>>>())
+        .map_err(|err| io_error(env, "list", &path, err))?;
+    names.sort();
+
+    env.stack.push(StackSlot::List(names.into_iter().map(StackSlot::String).collect()));
+    Ok(())
+}
+
+/// ( base part -- path ) an absolute part replaces the base
+fn path_join(env: &mut Environment) -> Result<(), RuntimeError> {
+    let part = pop_string(env, "path-join")?;
+    let base = pop_string(env, "path-join")?;
+    env.stack.push(StackSlot::String(Path::new(&base).join(part).to_string_lossy().into_owned()));
+    Ok(())
+}
+
+/// ( path -- name ) the last part of a path, empty if there is none like for `/`
+fn basename(env: &mut Environment) -> Result<(), RuntimeError> {
+    let path = pop_string(env, "basename")?;
+    let name = Path::new(&path).file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    env.stack.push(StackSlot::String(name));
+    Ok(())
+}
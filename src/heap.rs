@@ -0,0 +1,132 @@
+use crate::{Command, StackSlot};
+
+
+/// the heap is never collected while it holds fewer cells than this
+const MIN_COLLECTION_CELLS: usize = 4096;
+
+/// Counters about the heap, see `Environment::heap_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeapStats {
+    /// blocks that are currently allocated
+    pub blocks: usize,
+    /// cells in all allocated blocks
+    pub cells: usize,
+    /// blocks allocated since the environment was created
+    pub allocated: u64,
+    /// blocks freed by the garbage collector
+    pub freed: u64,
+    /// garbage collections that ran
+    pub collections: u64
+}
+
+/// Memory for `alloc`, made of blocks of cells that each hold one value.
+///
+/// A block is addressed by its index, indexes of freed blocks are reused. The heap doesn't know
+/// what refers to its blocks, `collect` is given the blocks that are still reachable from outside.
+#[derive(Debug, Clone)]
+pub struct Heap {
+    blocks: Vec<Option<Vec<StackSlot>>>,
+    free: Vec<usize>,
+    /// collect once this many cells are in use
+    threshold: usize,
+    stats: HeapStats
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap { blocks: Vec::new(), free: Vec::new(), threshold: MIN_COLLECTION_CELLS, stats: HeapStats::default() }
+    }
+}
+
+impl Heap {
+    /// a new block of `size` cells that all hold 0, returns its index
+    pub fn alloc(&mut self, size: usize) -> usize {
+        let block = Some(vec![StackSlot::Int(0); size]);
+        self.stats.blocks += 1;
+        self.stats.cells += size;
+        self.stats.allocated += 1;
+
+        match self.free.pop() {
+            Some(index) => {
+                self.blocks[index] = block;
+                index
+            },
+            None => {
+                self.blocks.push(block);
+                self.blocks.len() - 1
+            }
+        }
+    }
+
+    /// the cells of a block, `None` if there is no such block
+    pub fn block(&self, index: usize) -> Option<&Vec<StackSlot>> {
+        self.blocks.get(index).and_then(Option::as_ref)
+    }
+
+    pub fn block_mut(&mut self, index: usize) -> Option<&mut Vec<StackSlot>> {
+        self.blocks.get_mut(index).and_then(Option::as_mut)
+    }
+
+    /// whether enough was allocated since the last collection to collect again
+    pub fn wants_collection(&self) -> bool {
+        self.stats.cells >= self.threshold
+    }
+
+    /// Free every block that can't be reached from the blocks in `roots`.
+    pub fn collect(&mut self, roots: Vec<usize>) {
+        let mut marked = vec![false; self.blocks.len()];
+        let mut pending = roots;
+
+        while let Some(index) = pending.pop() {
+            if marked.get(index) != Some(&false) {
+                continue;
+            }
+            marked[index] = true;
+            if let Some(block) = &self.blocks[index] {
+                for slot in block {
+                    slot_references(slot, &mut pending);
+                }
+            }
+        }
+
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            if !marked[index] {
+                if let Some(cells) = block.take() {
+                    self.stats.blocks -= 1;
+                    self.stats.cells -= cells.len();
+                    self.stats.freed += 1;
+                    self.free.push(index);
+                }
+            }
+        }
+
+        self.stats.collections += 1;
+        self.threshold = MIN_COLLECTION_CELLS.max(self.stats.cells * 2);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+}
+
+/// add the blocks a value refers to, including those inside lists and maps
+pub(crate) fn slot_references(slot: &StackSlot, out: &mut Vec<usize>) {
+    match slot {
+        StackSlot::HeapReference(index, _) => out.push(*index),
+        StackSlot::List(items) => items.iter().for_each(|item| slot_references(item, out)),
+        StackSlot::Map(entries) => entries.values().for_each(|value| slot_references(value, out)),
+        StackSlot::Closure(_, captured) => captured.iter().for_each(|(_, value)| command_references(value, out)),
+        _ => {}
+    }
+}
+
+/// add the blocks a value stored in a definition cell refers to
+pub(crate) fn command_references(command: &Command, out: &mut Vec<usize>) {
+    match command {
+        Command::HeapReference(index, _) => out.push(*index),
+        Command::Pushl(items) => items.iter().for_each(|item| slot_references(item, out)),
+        Command::Pushm(entries) => entries.values().for_each(|value| slot_references(value, out)),
+        Command::Closure(_, captured) => captured.iter().for_each(|(_, value)| command_references(value, out)),
+        _ => {}
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::prelude::*;
+use std::{thread, time};
+
+use termion::raw::IntoRawMode;
+use termion::{async_stdin, AsyncReader};
+
+
+/// Backend for every command that touches the console (`print`, `eprint`, `getc`, `__sleep`, `STACK`).
+pub trait Io {
+    fn print(&mut self, text: &str);
+
+    /// write diagnostics, kept apart from the regular output
+    fn eprint(&mut self, text: &str);
+
+    /// read a single byte without blocking, `None` if no input is available
+    fn getc(&mut self) -> Option<u8>;
+
+    fn sleep(&mut self, duration: time::Duration);
+}
+
+impl fmt::Debug for dyn Io {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Io")
+    }
+}
+
+/// Reads from and writes to the real terminal.
+///
+/// The stdin reader is created on the first `getc` and then reused, so scripts that never read
+/// input don't need a terminal.
+#[derive(Default)]
+pub struct TerminalIo {
+    stdin: Option<AsyncReader>
+}
+
+impl TerminalIo {
+    pub fn new() -> Self {
+        TerminalIo::default()
+    }
+}
+
+impl Io for TerminalIo {
+    fn print(&mut self, text: &str) {
+        print!("{}", text);
+    }
+
+    fn eprint(&mut self, text: &str) {
+        std::io::stdout().flush().ok();
+        eprint!("{}", text);
+    }
+
+    fn getc(&mut self) -> Option<u8> {
+        let _stdout = std::io::stdout().into_raw_mode();
+        let stdin = self.stdin.get_or_insert_with(async_stdin);
+        let mut input = [0; 1];
+
+        stdin.read_exact(&mut input).ok().map(|_| input[0])
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        std::io::stdout().flush().ok();
+        thread::sleep(duration);
+    }
+}
+
+/// Keeps all output in memory and serves input from a buffer, useful for tests and embedding.
+#[derive(Default, Debug)]
+pub struct CaptureIo {
+    output: String,
+    errors: String,
+    input: VecDeque<u8>,
+    slept: time::Duration
+}
+
+impl CaptureIo {
+    pub fn new() -> Self {
+        CaptureIo::default()
+    }
+
+    pub fn with_input(input: &str) -> Self {
+        CaptureIo { input: input.bytes().collect(), ..CaptureIo::default() }
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// what was written with `eprint`
+    pub fn errors(&self) -> &str {
+        &self.errors
+    }
+
+    /// return the output collected so far and start over with an empty buffer
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn push_input(&mut self, input: &str) {
+        self.input.extend(input.bytes());
+    }
+
+    /// total time the script asked to sleep, sleeping itself returns immediately
+    pub fn slept(&self) -> time::Duration {
+        self.slept
+    }
+}
+
+impl Io for CaptureIo {
+    fn print(&mut self, text: &str) {
+        self.output.push_str(text);
+    }
+
+    fn eprint(&mut self, text: &str) {
+        self.errors.push_str(text);
+    }
+
+    fn getc(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        self.slept += duration;
+    }
+}
+
+/// Discards all output and never has any input.
+#[derive(Default, Debug)]
+pub struct NullIo;
+
+impl Io for NullIo {
+    fn print(&mut self, _text: &str) {}
+
+    fn eprint(&mut self, _text: &str) {}
+
+    fn getc(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn sleep(&mut self, _duration: time::Duration) {}
+}
@@ -1,14 +1,34 @@
 mod arithparser;
-
-use std::fs;
-use std::collections::HashMap;
+mod builtins;
+mod bytecode;
+mod debugger;
+mod error;
+mod files;
+mod heap;
+mod io;
+mod library;
+mod testrunner;
+mod tokenizer;
+
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time;
+use std::convert::TryFrom;
+
 use regex::Regex;
-use std::convert::TryInto;
-use termion::raw::IntoRawMode;
-use termion::async_stdin;
-use std::{thread, time};
-use std::io::prelude::*;
+
+pub use crate::bytecode::{Bytecode, BytecodeError};
+pub use crate::debugger::Debugger;
+pub use crate::error::{Error, RuntimeError, SyntaxError, ErrorKind, TraceFrame, Location};
+pub use crate::heap::HeapStats;
+use crate::files::Handles;
+use crate::heap::Heap;
+pub use crate::io::{Io, TerminalIo, CaptureIo, NullIo};
+pub use crate::testrunner::{discover_tests, run_test_file, report, ReportFormat, TestOutcome, TestResult};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Visibility {
@@ -16,16 +36,39 @@ pub enum Visibility {
     Private
 }
 
+/// Position of a token in one of the files loaded into an `Environment`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize
+}
+
+/// Source code loaded into an `Environment`, `Span::file` is an index into the list of these.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String
+}
+
+/// Where a command came from. Commands that were generated from a token, like the `get` of
+/// `@name$`, are invisible but still point to the token.
 #[derive(Debug, Clone)]
 pub enum SourceReference {
-    Visible(String),
-    Invisible
+    Visible(String, Span),
+    Invisible(Span)
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Pushn(f64),
+    Pushi(i64),
     Pushs(String),
+    /// a list stored in a definition cell
+    Pushl(Vec<StackSlot>),
+    /// a map stored in a definition cell
+    Pushm(BTreeMap<MapKey, StackSlot>),
     Define(Visibility, usize),
     EndDefine,
     Jmp,
@@ -36,6 +79,16 @@ pub enum Command {
     Mul,
     Div,
     Mod,
+    /// integer division, rounds towards zero
+    IntDiv,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    ToInt,
+    ToFloat,
     GT,
     GE,
     LT,
@@ -44,6 +97,10 @@ pub enum Command {
     NE,
     NamedReference(String, usize),
     AbsoluteReference(usize),
+    /// a heap reference stored in a definition cell
+    HeapReference(usize, usize),
+    /// a closure stored in a definition cell
+    Closure(usize, Rc<[(usize, Command)]>),
     Print,
     Getc,
     Not,
@@ -55,23 +112,61 @@ pub enum Command {
     ArrowPut,
     AddressOf,
     Lambda(usize),
+    /// `lambda [ a b ]`, comes right before its `Lambda` and pushes a closure that captured the
+    /// current values of the listed locals. The capture is by value: the closure doesn't see later
+    /// changes to the locals and what it writes to them is gone once it returns.
+    Capture(Vec<String>),
+    /// pop a number and skip the next n commands if it is 0
+    SkipIfZero(usize),
+    /// pop a number and skip the next n commands if it is not 0
+    SkipIfNotZero(usize),
     Return,
     Pull,
     Sleep,
+    /// `[`, values pushed from here on are collected by the matching `ListEnd`
+    ListStart,
+    /// `]`, replace the values pushed since the matching `ListStart` by a list of them
+    ListEnd,
+    /// `try`, the offsets lead to its `catch`, its `finally` and the closing `in`, 0 for a missing part
+    Try(usize, usize, usize),
+    /// `catch`, reached when the `try` block succeeded, skips the handler
+    Catch(usize),
+    /// `finally`, the code from here on runs however the `try` block was left
+    Finally,
+    /// the `in` that closes a `try`, raises the error again that is still pending
+    EndTry,
+    /// check that the stack holds an argument for each parameter of the called definition
+    Parameters(Vec<String>),
+    /// `import as name`, include a file and make its module available as `name`
+    Import(String),
+    /// bring the names of a module into scope without their prefix
+    Open,
+    /// `export [ a b ]`, the full names of what a module shows to `import` and `open`
+    Export(Vec<String>),
 
     Nop,
     Include,
     PrintStack,
+    Breakpoint,
     Bytes,
+    Native(usize),
     Placeholder
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StackSlot {
     Number(f64),
+    Int(i64),
     String(String),
+    List(Vec<StackSlot>),
+    Map(BTreeMap<MapKey, StackSlot>),
     NamedReference(String, usize),
-    AbsoluteReference(usize)
+    AbsoluteReference(usize),
+    /// block of the heap and offset into it, created by `alloc`
+    HeapReference(usize, usize),
+    /// a lambda together with the cells of the locals it captured and their values, they are
+    /// shared so `Command` and `StackSlot` don't grow and copying a closure is cheap
+    Closure(usize, Rc<[(usize, Command)]>)
 }
 
 #[derive(Clone, Debug)]
@@ -79,11 +174,72 @@ pub struct Stack {
     stack: Vec<StackSlot>
 }
 
-#[derive(Debug)]
-pub struct RuntimeError {
-    msg: String,
-    call_stack: Vec<(usize, usize)>,
-    env: Environment
+/// Resource limits for running untrusted code, `None` means no limit.
+///
+/// Executed instructions and time spent running are counted from the moment the limits are set
+/// with `Environment::set_limits`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub instructions: Option<u64>,
+    pub time: Option<time::Duration>,
+    /// number of values on the data stack
+    pub stack_size: Option<usize>,
+    /// number of nested jumps that haven't returned yet
+    pub call_depth: Option<usize>,
+    /// number of commands in the program, including everything that was included
+    pub program_size: Option<usize>,
+    /// number of cells in the heap blocks that are allocated
+    pub heap_size: Option<usize>
+}
+
+/// the most cells a single `_N` or `alloc` may reserve, whatever the limits are
+pub(crate) const MAX_RESERVED_CELLS: usize = 1 << 20;
+
+/// A call that hasn't returned yet.
+///
+/// The locals of the called definition, the definitions inside of it that are declared with `_`
+/// like `x is _ in`, are shared program cells. The frame keeps the values they had before the call
+/// and puts them back when it returns. So every invocation works on its own copy, even if the
+/// definition calls itself. Definitions with a value like `count is 0 in` keep it between calls.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    /// the jump that made the call
+    pub(crate) caller: usize,
+    /// where the call went
+    pub(crate) target: usize,
+    saved: Vec<(usize, Command)>
+}
+
+/// which part of a `try` is running
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Body,
+    Catch,
+    Finally
+}
+
+/// A `try` that was entered and not left yet.
+#[derive(Debug, Clone)]
+struct Handler {
+    /// position of the `try` command
+    start: usize,
+    stage: Stage,
+    /// sizes of the data stack, the call stack and the open lists when the `try` was entered
+    stack: usize,
+    calls: usize,
+    lists: usize,
+    /// the error that is raised again once the `finally` block is done
+    pending: Option<RuntimeError>
+}
+
+/// signature of a function implemented in rust that can be called from scripts
+pub type NativeFn = dyn Fn(&mut Environment) -> Result<(), RuntimeError>;
+
+/// a named host function, see `Environment::register_native`
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: String,
+    func: Rc<NativeFn>
 }
 
 #[derive(Clone, Debug)]
@@ -93,41 +249,474 @@ pub struct Environment {
     definitions: HashMap<String, usize>,
     program: Vec<Command>,
     source: Vec<SourceReference>,
+    files: Vec<SourceFile>,
     idx: usize,
     pub execute: bool,
     level: u32,
-    includes: Vec<String>
+    /// the files that were included, by their canonical path
+    includes: Vec<String>,
+    /// directories to look for included files in, see `set_search_path`
+    search_path: Vec<PathBuf>,
+    /// the modules made available by `import` and `open` as (alias, module), the alias of an
+    /// opened module is empty
+    imports: Vec<(String, String)>,
+    /// the names that `import` and `open` added, with the full name of the definition each one
+    /// stands for
+    links: HashMap<String, String>,
+    call_stack: Vec<Frame>,
+    /// the cells of the locals declared directly inside each definition and lambda, by its position
+    locals: HashMap<usize, Vec<usize>>,
+    /// stack sizes at the `[` of the list literals that are being built
+    list_starts: Vec<usize>,
+    /// the `try` blocks that are being run, innermost last
+    handlers: Vec<Handler>,
+    heap: Heap,
+    /// compiled patterns of the `re-` natives
+    regexes: HashMap<String, Regex>,
+    /// files opened by scripts
+    handles: Rc<RefCell<Handles>>,
+    natives: Vec<NativeFunction>,
+    io: Rc<RefCell<dyn Io>>,
+    debugger: Option<Rc<RefCell<Debugger>>>,
+    limits: Limits,
+    /// the command line arguments given to the script, see `argv`
+    args: Vec<String>,
+    /// the status passed to `exit`, set once the script asked to stop
+    exit_code: Option<i32>,
+    /// the values below this height aren't arguments, see `stack-floor`
+    stack_floor: usize,
+    executed: u64,
+    elapsed: time::Duration
+}
+
+impl fmt::Display for StackSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackSlot::Number(n) => write!(f, "{}", n),
+            StackSlot::Int(n) => write!(f, "{}", n),
+            StackSlot::String(s) => write!(f, "{}", s.replace("\\n", "\n")),
+            StackSlot::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_item(f, item)?;
+                }
+                write!(f, "]")
+            },
+            StackSlot::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", key)?;
+                    write_item(f, value)?;
+                }
+                write!(f, "}}")
+            },
+            StackSlot::NamedReference(r, p) => write!(f, "@{}+{}", r, p),
+            StackSlot::AbsoluteReference(p) => write!(f, "@{}", p),
+            StackSlot::HeapReference(block, offset) => write!(f, "<heap {}>+{}", block, offset),
+            StackSlot::Closure(position, _) => write!(f, "<closure @{}>", position)
+        }
+    }
+}
+
+/// a value inside a list or map, strings are quoted so `["a b"]` and `["a", "b"]` look different
+fn write_item(f: &mut fmt::Formatter<'_>, item: &StackSlot) -> fmt::Result {
+    match item {
+        StackSlot::String(s) => write!(f, "{:?}", s),
+        item => write!(f, "{}", item)
+    }
+}
+
+/// A key of a map. Floats that hold an integer are the same key as that integer, numbers are
+/// ordered before strings.
+#[derive(Clone)]
+pub enum MapKey {
+    Int(i64),
+    Float(f64),
+    String(String)
+}
+
+impl MapKey {
+    /// the key for a value, `None` if the value can't be used as a key
+    pub fn from_slot(slot: &StackSlot) -> Option<MapKey> {
+        match slot {
+            StackSlot::String(s) => Some(MapKey::String(s.clone())),
+            slot => match integer(slot) {
+                Some(n) => Some(MapKey::Int(n)),
+                None => match slot {
+                    StackSlot::Number(n) => Some(MapKey::Float(*n)),
+                    _ => None
+                }
+            }
+        }
+    }
+
+    pub fn to_slot(&self) -> StackSlot {
+        match self {
+            MapKey::Int(n) => StackSlot::Int(*n),
+            MapKey::Float(n) => StackSlot::Number(*n),
+            MapKey::String(s) => StackSlot::String(s.clone())
+        }
+    }
+}
+
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MapKey::Int(l), MapKey::Int(r)) => l.cmp(r),
+            (MapKey::Int(l), MapKey::Float(r)) => (*l as f64).total_cmp(r),
+            (MapKey::Float(l), MapKey::Int(r)) => l.total_cmp(&(*r as f64)),
+            (MapKey::Float(l), MapKey::Float(r)) => l.total_cmp(r),
+            (MapKey::String(l), MapKey::String(r)) => l.cmp(r),
+            (MapKey::String(_), _) => Ordering::Greater,
+            (_, MapKey::String(_)) => Ordering::Less
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MapKey {}
+
+impl fmt::Debug for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::Int(n) => write!(f, "{}", n),
+            MapKey::Float(n) => write!(f, "{}", n),
+            MapKey::String(s) => write!(f, "{:?}", s)
+        }
+    }
+}
+
+/// equality as seen by `==`, integers and floats compare by value, lists and maps compare their
+/// items, heap references are equal if they point to the same cell, closures if they run the same
+/// code with the same captured values and other values of different types are never equal
+fn slots_equal(left: &StackSlot, right: &StackSlot) -> bool {
+    match (left, right) {
+        (StackSlot::String(l), StackSlot::String(r)) => l == r,
+        (StackSlot::List(l), StackSlot::List(r)) =>
+            l.len() == r.len() && l.iter().zip(r).all(|(l, r)| slots_equal(l, r)),
+        (StackSlot::Map(l), StackSlot::Map(r)) =>
+            l.len() == r.len() && l.iter().zip(r).all(|((lk, lv), (rk, rv))| lk == rk && slots_equal(lv, rv)),
+        (StackSlot::HeapReference(lb, lo), StackSlot::HeapReference(rb, ro)) => lb == rb && lo == ro,
+        (StackSlot::Closure(lp, lc), StackSlot::Closure(rp, rc)) => lp == rp && lc == rc,
+        _ => match numbers(left, right) {
+            Some(Numbers::Ints(l, r)) => l == r,
+            Some(Numbers::Floats(l, r)) => l == r,
+            None => false
+        }
+    }
+}
+
+/// operands of an arithmetic operator, integers are only kept if both sides are integers
+enum Numbers {
+    Ints(i64, i64),
+    Floats(f64, f64)
+}
+
+fn numbers(left: &StackSlot, right: &StackSlot) -> Option<Numbers> {
+    match (left, right) {
+        (StackSlot::Int(l), StackSlot::Int(r)) => Some(Numbers::Ints(*l, *r)),
+        (StackSlot::Int(l), StackSlot::Number(r)) => Some(Numbers::Floats(*l as f64, *r)),
+        (StackSlot::Number(l), StackSlot::Int(r)) => Some(Numbers::Floats(*l, *r as f64)),
+        (StackSlot::Number(l), StackSlot::Number(r)) => Some(Numbers::Floats(*l, *r)),
+        _ => None
+    }
+}
+
+/// the value of an integer or of a float without fractional part
+fn integer(slot: &StackSlot) -> Option<i64> {
+    match slot {
+        StackSlot::Int(n) => Some(*n),
+        StackSlot::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Some(*n as i64),
+        _ => None
+    }
+}
+
+/// the character with the unicode code point `code`, numbers are added to strings as characters
+fn code_point(env: &Environment, code: i64) -> Result<char, RuntimeError> {
+    u32::try_from(code).ok().and_then(char::from_u32)
+        .ok_or_else(|| RuntimeError::new(ErrorKind::Conversion, format!("{} is not a unicode code point", code), env))
+}
+
+fn is_reference(slot: &StackSlot) -> bool {
+    matches!(slot, StackSlot::NamedReference(_, _) | StackSlot::AbsoluteReference(_))
 }
 
 impl Environment {
+    /// a new environment with the built-in native functions registered
     pub fn new(program: Vec<Command>, source: Vec<SourceReference>) -> Self {
+        let mut env = Environment::empty(program, source);
+        builtins::register(&mut env);
+        env
+    }
+
+    fn empty(program: Vec<Command>, source: Vec<SourceReference>) -> Self {
         Environment {
             prefix: Vec::new(),
             stack: Stack { stack: Vec::new() },
             definitions: HashMap::new(),
             program,
             source,
+            files: Vec::new(),
             idx: 0,
             execute: true,
             level: 0,
-            includes: Vec::new()
+            includes: Vec::new(),
+            search_path: library::search_path_from_env(),
+            imports: Vec::new(),
+            links: HashMap::new(),
+            call_stack: Vec::new(),
+            locals: HashMap::new(),
+            list_starts: Vec::new(),
+            handlers: Vec::new(),
+            heap: Heap::default(),
+            regexes: HashMap::new(),
+            handles: Rc::new(RefCell::new(Handles::default())),
+            natives: Vec::new(),
+            io: Rc::new(RefCell::new(TerminalIo::new())),
+            debugger: None,
+            limits: Limits::default(),
+            args: Vec::new(),
+            exit_code: None,
+            stack_floor: 0,
+            executed: 0,
+            elapsed: time::Duration::ZERO
         }
     }
 
-    fn from(from: Environment) -> Self {
-        Environment {
-            prefix: from.prefix,
-            stack: from.stack,
-            definitions: from.definitions,
-            program: from.program,
-            source: from.source,
-            idx: from.idx,
-            execute: from.execute,
-            level: from.level,
-            includes: from.includes
+    /// Let scripts use the file system through `read-file`, `file-open` and the other file natives.
+    ///
+    /// They are left out unless this is called, so an embedder can run scripts it doesn't trust.
+    /// Call it before loading code, natives are looked up when code is lexed.
+    pub fn enable_files(&mut self) {
+        files::register(self);
+    }
+
+    /// Replace the console backend, the default talks to the terminal.
+    ///
+    /// Keep a clone of the `Rc` to inspect a `CaptureIo` after running a script.
+    pub fn set_io(&mut self, io: Rc<RefCell<dyn Io>>) {
+        self.io = io;
+    }
+
+    pub fn io(&self) -> &Rc<RefCell<dyn Io>> {
+        &self.io
+    }
+
+    /// Stop before instructions to inspect the program, `None` runs without a debugger.
+    pub fn set_debugger(&mut self, debugger: Option<Rc<RefCell<Debugger>>>) {
+        self.debugger = debugger;
+    }
+
+    /// Restrict what the code run from now on may use, exceeding a limit is a runtime error.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.executed = 0;
+        self.elapsed = time::Duration::ZERO;
+    }
+
+    /// The arguments scripts get from `argv`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// The directories that `include` and `import` look in after the directory of the including
+    /// file and before the standard library. It starts out as `HORRIBLE_PATH`.
+    pub fn set_search_path(&mut self, dirs: Vec<PathBuf>) {
+        self.search_path = dirs;
+    }
+
+    pub fn search_path(&self) -> &[PathBuf] {
+        &self.search_path
+    }
+
+    /// The status the script passed to `exit`, `None` while it hasn't called it.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// instructions executed since the limits were set
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Free the heap blocks that can't be reached from the stack or a definition cell, including the
+    /// values of locals that a running call will put back, this also
+    /// happens on its own when `alloc` needs space.
+    pub fn collect_garbage(&mut self) {
+        let mut roots = Vec::new();
+        for slot in &self.stack.stack {
+            heap::slot_references(slot, &mut roots);
+        }
+        for command in &self.program {
+            heap::command_references(command, &mut roots);
+        }
+        for (_, command) in self.call_stack.iter().flat_map(|frame| &frame.saved) {
+            heap::command_references(command, &mut roots);
+        }
+        // the error a `finally` raises again once it is done
+        for err in self.handlers.iter().filter_map(|handler| handler.pending.as_ref()) {
+            for slot in err.operands().iter().chain(err.stack()) {
+                heap::slot_references(slot, &mut roots);
+            }
+        }
+        self.heap.collect(roots);
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// enter the definition at `target` from the jump at `idx`, the values of its locals are kept
+    fn call(&mut self, target: usize) {
+        let saved = self.locals.get(&target).map_or_else(Vec::new, |cells| {
+            cells.iter().map(|&cell| (cell, self.program[cell].clone())).collect()
+        });
+        self.call_stack.push(Frame { caller: self.idx, target, saved });
+    }
+
+    /// enter a closure, its captured locals hold the captured values until it returns
+    ///
+    /// Kept out of line, so jumping to a definition doesn't pay for closures.
+    #[cold]
+    #[inline(never)]
+    fn call_closure(&mut self, target: usize, captured: &[(usize, Command)]) {
+        self.call(target);
+        let frame = self.call_stack.last_mut().unwrap();
+        for (cell, command) in captured {
+            frame.saved.push((*cell, std::mem::replace(&mut self.program[*cell], command.clone())));
         }
     }
 
+    /// leave the innermost call and put back the values of its locals, returns the caller
+    fn leave(&mut self) -> Option<usize> {
+        let frame = self.call_stack.pop()?;
+        for (cell, command) in frame.saved.into_iter().rev() {
+            self.program[cell] = command;
+        }
+        Some(frame.caller)
+    }
+
+    /// leave calls until only `depth` are left
+    pub(crate) fn unwind(&mut self, depth: usize) {
+        while self.call_stack.len() > depth {
+            self.leave();
+        }
+    }
+
+    fn check_limits(&self, started: time::Instant) -> Result<(), RuntimeError> {
+        let limits = &self.limits;
+
+        let exceeded = if limits.instructions.is_some_and(|max| self.executed > max) {
+            format!("executed more than {} instructions", limits.instructions.unwrap())
+        } else if limits.stack_size.is_some_and(|max| self.stack.stack.len() > max) {
+            format!("the stack holds more than {} values", limits.stack_size.unwrap())
+        } else if limits.call_depth.is_some_and(|max| self.call_stack.len() > max) {
+            format!("more than {} nested calls", limits.call_depth.unwrap())
+        } else if limits.program_size.is_some_and(|max| self.program.len() > max) {
+            format!("the program has more than {} commands", limits.program_size.unwrap())
+        // looking at the clock is slow compared to an instruction, only do it now and then
+        } else if self.executed.is_multiple_of(1024) && limits.time.is_some_and(|max| self.elapsed + started.elapsed() > max) {
+            format!("ran for more than {:?}", limits.time.unwrap())
+        } else {
+            return Ok(());
+        };
+
+        Err(RuntimeError::new(ErrorKind::LimitExceeded, format!("limit exceeded: {}", exceeded), self))
+    }
+
+    /// let the debugger know that the cell at `position` was written
+    fn written(&self, position: usize) {
+        if let Some(debugger) = &self.debugger {
+            debugger.borrow_mut().written(self, position);
+        }
+    }
+
+    /// Make a rust function callable from scripts under `name`.
+    ///
+    /// Every occurrence of the name in code that is lexed afterwards calls `func`, which works
+    /// directly on the data stack. Where the word names a definition, like in `len is` or
+    /// `len addr`, it stays a name, so scripts can define words that are also natives. Registering
+    /// a name twice replaces the previous function, also for code that was already lexed.
+    pub fn register_native<F>(&mut self, name: &str, func: F)
+    where F: Fn(&mut Environment) -> Result<(), RuntimeError> + 'static {
+        let native = NativeFunction { name: name.into(), func: Rc::new(func) };
+
+        if let Some(existing) = self.natives.iter_mut().find(|n| n.name == name) {
+            *existing = native;
+        } else {
+            self.natives.push(native);
+        }
+    }
+
+    /// create a runtime error at the current position, for use in native functions
+    pub fn error(&self, kind: ErrorKind, msg: impl Into<String>) -> RuntimeError {
+        RuntimeError::new(kind, msg, self)
+    }
+
+    /// name of the definition starting at `position`, used for stack traces
+    fn name_of(&self, position: usize) -> String {
+        if let Some((name, _)) = self.definitions.iter().find(|(_, p)| **p == position) {
+            return name.clone();
+        }
+
+        match self.program.get(position) {
+            Some(Command::Lambda(_)) => String::from("<lambda>"),
+            _ => format!("<{}>", position)
+        }
+    }
+
+    fn syntax_error(&self, msg: impl Into<String>) -> SyntaxError {
+        SyntaxError::new(msg, self.location(self.idx))
+    }
+
+    /// remember a piece of source code, returns the index to use in its spans
+    fn add_file(&mut self, name: &str, text: &str) -> usize {
+        self.files.push(SourceFile { name: name.into(), text: text.into() });
+        self.files.len() - 1
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// file, line and column of the instruction at `idx`
+    pub fn location(&self, idx: usize) -> Option<Location> {
+        self.span_location(self.source.get(idx)?.span())
+    }
+
+    fn span_location(&self, span: &Span) -> Option<Location> {
+        let file = self.files.get(span.file)?;
+
+        Some(Location {
+            file: file.name.clone(),
+            line: span.line,
+            column: span.column,
+            length: span.length,
+            text: file.text.lines().nth(span.line - 1).unwrap_or_default().into()
+        })
+    }
+
     fn resolve_reference(definitions: &HashMap<String, usize>, name: String) -> Result<usize, String> {
         if definitions.contains_key(&name) {
             Ok(definitions[&name])
@@ -149,6 +738,7 @@ impl Environment {
 
     /// when a definition is encountered while exectuting, add it without prefix to the namespace
     fn define_here(&mut self, s: String, idx: usize) {
+        self.links.remove(&s);
         if let std::collections::hash_map::Entry::Vacant(e) = self.definitions.entry(s.clone()) {
             e.insert(idx);
         } else {
@@ -157,30 +747,44 @@ impl Environment {
     }
 }
 
+impl NativeFunction {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
 impl Stack {
-    fn push(&mut self, item: StackSlot) {
+    pub fn push(&mut self, item: StackSlot) {
         self.stack.push(item);
     }
 
-    fn pop(&mut self) -> Option<StackSlot> {
+    pub fn pop(&mut self) -> Option<StackSlot> {
         self.stack.pop()
     }
 
-    fn pop_number(&mut self) -> Option<f64> {
+    /// pop an integer or a float, integers are converted to floats
+    pub fn pop_number(&mut self) -> Option<f64> {
         match self.stack.pop() {
             Some(StackSlot::Number(n)) => Some(n),
+            Some(StackSlot::Int(n)) => Some(n as f64),
             _ => None
         }
     }
 
-    fn pop_string(&mut self) -> Option<String> {
+    pub fn pop_string(&mut self) -> Option<String> {
         match self.stack.pop() {
             Some(StackSlot::String(s)) => Some(s),
             _ => None
         }
     }
 
-    fn pop_reference(&mut self) -> Option<(String, usize)> {
+    pub fn pop_reference(&mut self) -> Option<(String, usize)> {
         match self.stack.pop() {
             Some(StackSlot::NamedReference(s, n)) => Some((s, n)),
             _ => None
@@ -188,61 +792,251 @@ impl Stack {
     }
 }
 
-impl RuntimeError {
-    fn new(msg: String, call_stack: &[(usize, usize)], env: &Environment) -> Self {
-        RuntimeError {
-            msg, call_stack: call_stack.to_vec(), env: env.clone()
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.stack.iter().map(|e| format!("{:?}\n", e)).fold(String::default(), |acc, e| acc + &e))
+    }
+}
+
+impl Command {
+    /// the value stored in a definition cell, `None` if the cell holds code
+    fn value(&self) -> Option<StackSlot> {
+        match self {
+            Command::Pushn(n) => Some(StackSlot::Number(*n)),
+            Command::Pushi(n) => Some(StackSlot::Int(*n)),
+            Command::Pushl(items) => Some(StackSlot::List(items.clone())),
+            Command::Pushm(entries) => Some(StackSlot::Map(entries.clone())),
+            Command::Pushs(s) => Some(StackSlot::String(s.clone())),
+            Command::NamedReference(s, offset) => Some(StackSlot::NamedReference(String::from(&s[1..]), *offset)),
+            Command::AbsoluteReference(position) => Some(StackSlot::AbsoluteReference(*position)),
+            Command::HeapReference(block, offset) => Some(StackSlot::HeapReference(*block, *offset)),
+            Command::Closure(position, captured) => Some(StackSlot::Closure(*position, captured.clone())),
+            _ => None
         }
     }
 }
 
-impl fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let call_info = self.call_stack.iter()
-            .map(|f|
-                format!("{}",
-                    self.env.source[f.1 - 1]
-                )
-            ).collect::<Vec<String>>();
-        let info_start_idx = isize::max(0, self.env.idx as isize - 10) as usize;
-        let info_end_idx = isize::min(self.env.source.len() as isize, self.env.idx as isize + 10) as usize;
-        write!(f,
-            "RuntimeError {}: {}\nstack: {:#?}\ncallstack: {:#?}\n",
-            self.env.source[self.env.idx],
-            self.msg,
-            self.env.stack,
-            call_info)?;
-        write!(f, "Around here: {}", self.env.source[info_start_idx..info_end_idx].iter()
-            .map(|sr| match sr {
-                SourceReference::Visible(s) => format!(" {}", s),
-                SourceReference::Invisible => String::new(),
-            }).collect::<String>()
-        )
+impl StackSlot {
+    /// the command that pushes this value, to store it in a definition cell
+    fn into_cell(self) -> Command {
+        match self {
+            StackSlot::Number(n) => Command::Pushn(n),
+            StackSlot::Int(n) => Command::Pushi(n),
+            StackSlot::String(s) => Command::Pushs(s),
+            StackSlot::List(items) => Command::Pushl(items),
+            StackSlot::Map(entries) => Command::Pushm(entries),
+            StackSlot::NamedReference(r, offset) => Command::NamedReference(String::from("@") + r.as_ref(), offset),
+            StackSlot::AbsoluteReference(position) => Command::AbsoluteReference(position),
+            StackSlot::HeapReference(block, offset) => Command::HeapReference(block, offset),
+            StackSlot::Closure(position, captured) => Command::Closure(position, captured)
+        }
     }
 }
 
-impl fmt::Display for Stack {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.stack.iter().map(|e| format!("{:?}\n", e)).fold(String::default(), |acc, e| acc + &e))
+impl SourceReference {
+    pub fn span(&self) -> &Span {
+        match self {
+            SourceReference::Visible(_, span) | SourceReference::Invisible(span) => span
+        }
     }
 }
 
 impl fmt::Display for SourceReference {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", match self {
-            SourceReference::Visible(name) => name,
-            SourceReference::Invisible => ""
+            SourceReference::Visible(name, _) => name,
+            SourceReference::Invisible(_) => ""
         })
     }
 }
 
+/// pop the two operands of a binary operator, the top of the stack is the right one
+fn pop_operands(env: &mut Environment, action: &str) -> Result<(StackSlot, StackSlot), RuntimeError> {
+    match (env.stack.pop(), env.stack.pop()) {
+        (Some(right), Some(left)) => Ok((left, right)),
+        _ => Err(RuntimeError::new(ErrorKind::StackUnderflow, format!("stack underflow while {}!", action), env))
+    }
+}
+
+/// Apply an arithmetic operator. Two integers use `int`, which returns `None` on overflow or when
+/// dividing by zero, everything else is calculated with floats.
+fn arithmetic(env: &Environment, left: &StackSlot, right: &StackSlot, int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64)
+    -> Result<StackSlot, RuntimeError> {
+    match numbers(left, right) {
+        Some(Numbers::Ints(l, r)) => int(l, r).map(StackSlot::Int).ok_or_else(|| {
+            // only division fails for a zero on the right
+            let msg = if r == 0 { "division by zero" } else { "integer overflow" };
+            RuntimeError::new(ErrorKind::Arithmetic, msg, env)
+        }),
+        Some(Numbers::Floats(l, r)) => Ok(StackSlot::Number(float(l, r))),
+        None => Err(RuntimeError::new(ErrorKind::TypeMismatch, "arithmetic is only supported for numbers", env))
+    }
+}
+
+/// move a reference `by` cells, it can't point before the start of the program
+fn offset_by(env: &Environment, offset: usize, by: i64) -> Result<usize, RuntimeError> {
+    i64::try_from(offset).ok()
+        .and_then(|offset| offset.checked_add(by))
+        .and_then(|offset| usize::try_from(offset).ok())
+        .ok_or_else(|| RuntimeError::new(ErrorKind::BadReference, format!("reference offset {} + {} is negative", offset, by), env))
+}
+
+/// `-by` for subtracting from a reference, `i64::MIN` has no negation
+fn negated(env: &Environment, by: i64) -> Result<i64, RuntimeError> {
+    by.checked_neg()
+        .ok_or_else(|| RuntimeError::new(ErrorKind::Arithmetic, format!("can't subtract {} from a reference", by), env))
+}
+
+/// the cell a heap reference points to
+fn heap_cell(env: &mut Environment, block: usize, offset: usize) -> Result<&mut StackSlot, RuntimeError> {
+    match env.heap.block(block).map(Vec::len) {
+        Some(len) if offset < len => Ok(&mut env.heap.block_mut(block).unwrap()[offset]),
+        Some(len) => Err(RuntimeError::new(ErrorKind::BadReference, format!("offset {} is outside of a heap block with {} cells", offset, len), env)),
+        None => Err(RuntimeError::new(ErrorKind::BadReference, "reference to a freed heap block", env))
+    }
+}
+
+/// the position a reference points to
+fn address(env: &Environment, reference: &StackSlot) -> Result<usize, RuntimeError> {
+    match reference {
+        StackSlot::NamedReference(name, offset) => env.definitions.get(name).map(|position| position + offset)
+            .ok_or_else(|| RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env)),
+        StackSlot::AbsoluteReference(position) => Ok(*position),
+        _ => Err(RuntimeError::new(ErrorKind::BadReference, "expected a reference", env))
+    }
+}
+
 fn run(env: &mut Environment) -> Result<(), RuntimeError> {
-    let mut stdin_reader = async_stdin();
-    let mut call_stack: Vec<(usize, usize)> = Vec::new();
+    let started = time::Instant::now();
+    env.handlers.clear();
+    let res = loop {
+        match execute(env, started) {
+            Ok(()) => break Ok(()),
+            Err(err) => if let Err(err) = recover(env, err) {
+                break Err(err);
+            }
+        }
+    };
+    env.elapsed += started.elapsed();
+
+    res
+}
+
+/// the positions of the `catch`, the `finally` and the closing `in` of the `try` at `start`
+fn try_parts(env: &Environment, start: usize) -> (Option<usize>, Option<usize>, usize) {
+    match env.program[start] {
+        Command::Try(catch, finally, end) => (
+            Some(start + catch).filter(|_| catch != 0),
+            Some(start + finally).filter(|_| finally != 0),
+            start + end
+        ),
+        _ => unreachable!("handlers are only created by `try`")
+    }
+}
+
+/// Drop the handlers of `try` blocks that were left without finishing them, by `return` or `loop?`
+/// for example, their `finally` blocks don't run. A handler is still active if the code running in
+/// the definition that entered the `try` is inside of it.
+fn prune_handlers(env: &mut Environment) {
+    while let Some(handler) = env.handlers.last() {
+        let position = match env.call_stack.len() {
+            len if len < handler.calls => None,
+            len if len == handler.calls => Some(env.idx),
+            _ => Some(env.call_stack[handler.calls].caller)
+        };
+        let (_, _, end) = try_parts(env, handler.start);
+        if position.is_some_and(|p| p > handler.start && p <= end) {
+            break;
+        }
+        env.handlers.pop();
+    }
+}
+
+/// the innermost active handler if the parts of its `try` are what `belongs` expects
+fn current_handler(env: &mut Environment, belongs: impl Fn((Option<usize>, Option<usize>, usize)) -> bool)
+    -> Option<&mut Handler> {
+    prune_handlers(env);
+    let start = env.handlers.last()?.start;
+    if belongs(try_parts(env, start)) { env.handlers.last_mut() } else { None }
+}
+
+/// The value a `catch` block gets for an error, a map with its kind, code, message and location.
+/// Errors from `throw` also carry the thrown value.
+fn error_value(err: &RuntimeError) -> StackSlot {
+    let mut entries = BTreeMap::new();
+    let mut add = |key: &str, value: StackSlot| entries.insert(MapKey::String(key.into()), value);
+
+    add("kind", StackSlot::String(err.kind().name().into()));
+    add("code", StackSlot::String(err.code().into()));
+    add("message", StackSlot::String(err.message().into()));
+    if let Some(location) = err.location() {
+        add("file", StackSlot::String(location.file.clone()));
+        add("line", StackSlot::Int(location.line as i64));
+        add("column", StackSlot::Int(location.column as i64));
+    }
+    if let (ErrorKind::Thrown, Some(value)) = (err.kind(), err.operands().first()) {
+        add("value", value.clone());
+    }
+    StackSlot::Map(entries)
+}
+
+/// Continue in the innermost `try` that can handle `err`, the error is returned if there is none.
+///
+/// The stacks are cut back to their size at the `try`. A `catch` block gets the error on the
+/// stack, without one the `finally` block runs and the error is raised again after it.
+fn recover(env: &mut Environment, err: RuntimeError) -> Result<(), RuntimeError> {
+    if matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::LimitExceeded) {
+        return Err(err);
+    }
+
+    prune_handlers(env);
+    let mut handler = match env.handlers.pop() {
+        Some(handler) if handler.stage != Stage::Finally => handler,
+        // an error in a `finally` block replaces the one that was pending
+        Some(_) => return recover(env, err),
+        None => return Err(err)
+    };
+    let (catch, finally, _) = try_parts(env, handler.start);
+
+    env.stack.stack.truncate(handler.stack);
+    env.unwind(handler.calls);
+    env.list_starts.truncate(handler.lists);
+
+    match (handler.stage, catch) {
+        (Stage::Body, Some(catch)) => {
+            env.stack.push(error_value(&err));
+            env.idx = catch + 1;
+            if finally.is_some() {
+                handler.stage = Stage::Catch;
+                env.handlers.push(handler);
+            }
+        },
+        _ => {
+            // the parser makes sure that a `try` without `catch` has a `finally`
+            env.idx = finally.expect("`try` without `catch` or `finally`") + 1;
+            handler.stage = Stage::Finally;
+            handler.pending = Some(err);
+            env.handlers.push(handler);
+        }
+    }
+    Ok(())
+}
+
+fn execute(env: &mut Environment, started: time::Instant) -> Result<(), RuntimeError> {
+    let limited = env.limits != Limits::default();
 
     while env.idx < env.program.len() { 
+        env.executed += 1;
+        if limited {
+            env.check_limits(started)?;
+        }
+
+        if let Some(debugger) = env.debugger.clone() {
+            debugger.borrow_mut().before_instruction(env)?;
+        }
+
         match &env.program[env.idx] {
-            def @ Command::Define(_, skip) => {
+            Command::Define(_, skip) => {
                 let here = env.idx;
                 env.idx += *skip;
                 if let Command::Pushs(string) = env.program[here - 1].clone() {
@@ -253,45 +1047,117 @@ fn run(env: &mut Environment) -> Result<(), RuntimeError> {
             Command::Nop => { },
             Command::Include => {
                 let filename = env.stack.pop_string()
-                    .ok_or_else(|| RuntimeError::new("expected file name for include".into(), &call_stack, env))?;
-
-                if env.includes.contains(&filename) {
-                    env.idx += 1;
-                    continue;
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::TypeMismatch, "expected file name for include", env))?;
+                include(env, &filename)?;
+            }
+            Command::Import(alias) => {
+                let alias = alias.clone();
+                let filename = env.stack.pop_string()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::TypeMismatch, "expected file name for import", env))?;
+                include(env, &filename)?;
+
+                // the module of `lists.hr` is the definition `lists`
+                let module = Path::new(&filename).file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+                if !env.definitions.contains_key(&module) {
+                    return Err(RuntimeError::new(ErrorKind::UnknownSymbol,
+                        format!("`{}` doesn't define the module `{}`", filename, module), env));
                 }
-
-                env.includes.push(filename.clone());
-                let content = fs::read_to_string(filename.clone())
-                    .or_else(|_err| fs::read_to_string(format!("lib/{}", filename)))
-                    .map_err(|err| RuntimeError::new(format!("unable to read include file: {}", err), &call_stack, env))?;
-
-                let result = lexer(content.to_string());
-                let tokens = result.program;
-                let source = result.source;
-
-                for (token, source_ref) in tokens.iter().zip(source.iter()).rev() {
-                    env.program.insert(env.idx + 1, token.clone());
-                    env.source.insert(env.idx + 1, source_ref.clone());
+                link_module(env, &alias, &module);
+                env.imports.push((alias, module));
+            },
+            Command::Open => {
+                let name = env.stack.pop_string()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::TypeMismatch, "expected module name for open", env))?;
+                let module = env.imports.iter()
+                    .find(|(alias, _)| *alias == name)
+                    .map_or(name, |(_, module)| module.clone());
+                if !env.definitions.contains_key(&module) {
+                    return Err(RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such module: `{}`", module), env));
                 }
-
-                parser(env);
-            }
+                link_module(env, "", &module);
+                env.imports.push((String::new(), module));
+            },
+            Command::Export(_) => { },
             Command::Pushn(n) => env.stack.push(StackSlot::Number(*n)),
+            Command::Pushi(n) => env.stack.push(StackSlot::Int(*n)),
+            Command::Pushl(items) => env.stack.push(StackSlot::List(items.clone())),
+            Command::Pushm(entries) => env.stack.push(StackSlot::Map(entries.clone())),
+            Command::ListStart => env.list_starts.push(env.stack.stack.len()),
+            Command::ListEnd => {
+                let start = env.list_starts.pop()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::InvalidOperation, "`]` without a matching `[`", env))?;
+                if start > env.stack.stack.len() {
+                    return Err(RuntimeError::new(ErrorKind::StackUnderflow, "values from before the `[` were removed from the stack", env));
+                }
+                let items = env.stack.stack.split_off(start);
+                env.stack.push(StackSlot::List(items));
+            },
             Command::Pushs(s) => {
                 env.stack.push(StackSlot::String(s.clone()))
             },
-            Command::EndDefine | Command::Return => if !call_stack.is_empty() {
+            Command::Parameters(names) => {
+                let available = env.stack.stack.len().saturating_sub(env.stack_floor);
+                if available < names.len() {
+                    let missing = names[..names.len() - available].iter()
+                        .map(|name| format!("`{}`", name))
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    let name = env.call_stack.last().map_or(String::from("<toplevel>"), |frame| env.name_of(frame.target));
+                    return Err(RuntimeError::new(ErrorKind::StackUnderflow,
+                        format!("`{}` is missing the argument for {}", name, missing), env));
+                }
+            },
+            Command::Try(..) => {
+                let start = env.idx;
+                let calls = env.call_stack.len();
+                // a `try` that is entered again by a loop replaces its old handler
+                env.handlers.retain(|handler| handler.start != start || handler.calls != calls);
+                env.handlers.push(Handler {
+                    start,
+                    stage: Stage::Body,
+                    stack: env.stack.stack.len(),
+                    calls,
+                    lists: env.list_starts.len(),
+                    pending: None
+                });
+            },
+            Command::Catch(skip) => {
+                let skip = *skip;
+                let here = env.idx;
+                let finally = matches!(env.program[here + skip], Command::Finally);
+                match current_handler(env, |parts| parts.0 == Some(here)) {
+                    Some(handler) if finally => handler.stage = Stage::Finally,
+                    Some(_) => { env.handlers.pop(); },
+                    None => {}
+                }
+                env.idx += skip;
+                continue;
+            },
+            Command::Finally => {
+                let here = env.idx;
+                if let Some(handler) = current_handler(env, |parts| parts.1 == Some(here)) {
+                    handler.stage = Stage::Finally;
+                }
+            },
+            Command::EndTry => {
+                let here = env.idx;
+                if current_handler(env, |parts| parts.2 == here).is_some() {
+                    if let Some(err) = env.handlers.pop().and_then(|handler| handler.pending) {
+                        return Err(err);
+                    }
+                }
+            },
+            Command::EndDefine | Command::Return => if let Some(return_idx) = env.leave() {
                 env.prefix.pop();
-                env.idx = call_stack.pop().unwrap().0 as usize;
+                env.idx = return_idx;
             },
             Command::LoopIf => {
-                let reference_name = call_stack.last()
-                    .ok_or_else(|| RuntimeError::new("can't use `loop?` on toplevel".into(), &call_stack, env))?;
-
-                let position = reference_name.1;
+                let position = env.call_stack.last()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::InvalidOperation, "can't use `loop?` on toplevel", env))?
+                    .target;
 
                 let n = env.stack.pop_number()
-                    .ok_or_else(|| RuntimeError::new("expected number for a loop".into(), &call_stack, env))?;
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::TypeMismatch, "expected number for a loop", env))?;
                 if n != 0.0 {
                     env.idx = position;
                 }
@@ -299,25 +1165,30 @@ fn run(env: &mut Environment) -> Result<(), RuntimeError> {
             Command::JmpIf => {
                 let reference = env.stack.pop();
                 let value = env.stack.pop_number()
-                    .ok_or_else(|| RuntimeError::new("expected number for a conditional jump".into(), &call_stack, env))?;
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::TypeMismatch, "expected number for a conditional jump", env))?;
 
                 if value != 0.0 {
                     match reference {
                         Some(StackSlot::NamedReference(n, offset)) => {
                             if env.definitions.contains_key(&n) {
                                 let next_idx = env.definitions[&n] + offset;
-                                call_stack.push((env.idx, next_idx));
+                                env.call(next_idx);
                                 env.idx = next_idx;
                             } else {
-                                return Err(RuntimeError::new("reference not found in definitions for `jump?`".into(), &call_stack, env));
+                                return Err(RuntimeError::new(ErrorKind::UnknownSymbol, "reference not found in definitions for `jump?`", env));
                             }
                         },
                         Some(StackSlot::AbsoluteReference(position)) => {
-                            call_stack.push((env.idx, position));
+                            env.call(position);
+                            env.idx = position;
+                        },
+                        Some(StackSlot::Closure(position, captured)) => {
+                            env.call_closure(position, &captured);
                             env.idx = position;
                         },
-                        _ => {
-                            return Err(RuntimeError::new("expected reference for a jump".into(), &call_stack, env));
+                        other => {
+                            return Err(RuntimeError::new(ErrorKind::BadReference, "expected reference for a jump", env)
+                                .with_operands(other.into_iter().collect()));
                         }
                     }
                 }
@@ -327,334 +1198,293 @@ fn run(env: &mut Environment) -> Result<(), RuntimeError> {
                     Some(StackSlot::NamedReference(n, offset)) => {
                         if env.definitions.contains_key(&n) {
                             let next_idx = env.definitions[&n] + offset;
-                            call_stack.push((env.idx, next_idx));
+                            env.call(next_idx);
                             env.idx = next_idx;
                         } else {
-                            return Err(RuntimeError::new("reference not found in definitions `jump`".into(), &call_stack, env));
+                            return Err(RuntimeError::new(ErrorKind::UnknownSymbol, "reference not found in definitions `jump`", env));
                         }
                     },
                     Some(StackSlot::AbsoluteReference(position)) => {
-                        call_stack.push((env.idx, position));
+                        env.call(position);
                         env.idx = position;
                     },
-                    _ => {
-                        return Err(RuntimeError::new("expected reference for a jump".into(), &call_stack, env));
+                    Some(StackSlot::Closure(position, captured)) => {
+                        env.call_closure(position, &captured);
+                        env.idx = position;
+                    },
+                    other => {
+                        return Err(RuntimeError::new(ErrorKind::BadReference, "expected reference for a jump", env)
+                            .with_operands(other.into_iter().collect()));
                     }
                 }
             }
             Command::Add => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (&right, &left) {
-                        env.stack.push(StackSlot::Number(l + r));
-                    } else if let (StackSlot::String(r), StackSlot::Number(l)) = (&right, &left) {
-                        if let Ok(character) = TryInto::<char>::try_into(*l as u8) {
-                            env.stack.push(StackSlot::String(
-                                format!("{}{}", character, r)
-                            ));
-                        } else {
-                            return Err(RuntimeError::new(format!("{} is no a valid ascii character", l), &call_stack, env));
-                        }
-                    } else if let (StackSlot::Number(r), StackSlot::String(l)) = (&right, &left) {
-                        if let Ok(character) = TryInto::<char>::try_into(*r as u8) {
-                            env.stack.push(StackSlot::String(
-                                format!("{}{}", l, character)
-                            ));
-                        } else {
-                            return Err(RuntimeError::new(format!("{} is no a valid ascii character", r), &call_stack, env));
-                        }
-                    } else if let (StackSlot::String(r), StackSlot::String(l)) = (&right, &left) {
-                        env.stack.push(StackSlot::String(
-                            format!("{}{}", l, r)
-                        ));
-                    } else if let (StackSlot::Number(r), StackSlot::NamedReference(name, l)) = (&right, &left) {
-                        env.stack.push(StackSlot::NamedReference(
-                            name.clone(), *r as usize + l
-                        ));
-                    } else if let (StackSlot::Number(r), StackSlot::AbsoluteReference(position)) = (&right, &left) {
-                        env.stack.push(StackSlot::AbsoluteReference(
-                            *r as usize + position
-                        ));
-                    } else {
-                        return Err(RuntimeError::new("add operator only supported for numbers or strings".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while adding!".into(), &call_stack, env));
-                }
+                let (left, right) = pop_operands(env, "adding")?;
+                let result = match (&left, &right) {
+                    (StackSlot::String(l), StackSlot::String(r)) => StackSlot::String(format!("{}{}", l, r)),
+                    (StackSlot::String(l), r) if integer(r).is_some() =>
+                        StackSlot::String(format!("{}{}", l, code_point(env, integer(r).unwrap())?)),
+                    (l, StackSlot::String(r)) if integer(l).is_some() =>
+                        StackSlot::String(format!("{}{}", code_point(env, integer(l).unwrap())?, r)),
+                    (StackSlot::NamedReference(name, offset), by) if integer(by).is_some() =>
+                        StackSlot::NamedReference(name.clone(), offset_by(env, *offset, integer(by).unwrap())?),
+                    (StackSlot::AbsoluteReference(position), by) if integer(by).is_some() =>
+                        StackSlot::AbsoluteReference(offset_by(env, *position, integer(by).unwrap())?),
+                    (StackSlot::HeapReference(block, offset), by) if integer(by).is_some() =>
+                        StackSlot::HeapReference(*block, offset_by(env, *offset, integer(by).unwrap())?),
+                    _ => arithmetic(env, &left, &right, i64::checked_add, |l, r| l + r)
+                        .map_err(|err| err.with_operands(vec![left.clone(), right.clone()]))?
+                };
+                env.stack.push(result);
             },
             Command::Sub => {
-                match (env.stack.pop(), env.stack.pop()) {
-                    (Some(StackSlot::Number(r)), Some(StackSlot::Number(l))) => {
-                        env.stack.push(StackSlot::Number(l - r));
-                    },
-                    (Some(StackSlot::NamedReference(name, offset)), Some(StackSlot::AbsoluteReference(position))) => {
-                        let r = env.definitions.get(&name)
-                            .ok_or_else(|| RuntimeError::new("reference not found in definitions for subtraction".into(), &call_stack, env))? + offset;
-
-                        env.stack.push(StackSlot::Number((position - r) as f64));
-                    },
-                    (Some(StackSlot::AbsoluteReference(position)), Some(StackSlot::NamedReference(name, offset))) => {
-                        let l = env.definitions.get(&name)
-                            .ok_or_else(|| RuntimeError::new("reference not found in definitions for subtraction".into(), &call_stack, env))? + offset;
-
-                        env.stack.push(StackSlot::Number((l - position) as f64));
-                    },
-                    (Some(StackSlot::NamedReference(rname, roffset)), Some(StackSlot::NamedReference(lname, loffset))) => {
-                        let r = env.definitions.get(&rname)
-                            .ok_or_else(|| RuntimeError::new("reference not found in definitions for subtraction".into(), &call_stack, env))? + roffset;
-                        let l = env.definitions.get(&lname)
-                            .ok_or_else(|| RuntimeError::new("reference not found in definitions for subtraction".into(), &call_stack, env))? + loffset;
-
-                        env.stack.push(StackSlot::Number((l - r) as f64));
-                    },
-                    (Some(StackSlot::AbsoluteReference(r)), Some(StackSlot::AbsoluteReference(position))) => {
-                        env.stack.push(StackSlot::Number((position - r) as f64));
-                    },
-                    _ => return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env))
+                let (left, right) = pop_operands(env, "subtracting")?;
+                let result = match (&left, &right) {
+                    (StackSlot::NamedReference(name, offset), by) if integer(by).is_some() =>
+                        StackSlot::NamedReference(name.clone(), offset_by(env, *offset, negated(env, integer(by).unwrap())?)?),
+                    (StackSlot::AbsoluteReference(position), by) if integer(by).is_some() =>
+                        StackSlot::AbsoluteReference(offset_by(env, *position, negated(env, integer(by).unwrap())?)?),
+                    (StackSlot::HeapReference(block, offset), by) if integer(by).is_some() =>
+                        StackSlot::HeapReference(*block, offset_by(env, *offset, negated(env, integer(by).unwrap())?)?),
+                    (StackSlot::HeapReference(lb, lo), StackSlot::HeapReference(rb, ro)) if lb == rb =>
+                        StackSlot::Int(*lo as i64 - *ro as i64),
+                    (l, r) if is_reference(l) && is_reference(r) =>
+                        StackSlot::Int(address(env, l)? as i64 - address(env, r)? as i64),
+                    _ => arithmetic(env, &left, &right, i64::checked_sub, |l, r| l - r)
+                        .map_err(|err| err.with_operands(vec![left.clone(), right.clone()]))?
                 };
+                env.stack.push(result);
             },
             Command::Mul => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(l * r));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while multiplying!".into(), &call_stack, env));
-                }
+                let (left, right) = pop_operands(env, "multiplying")?;
+                let result = arithmetic(env, &left, &right, i64::checked_mul, |l, r| l * r)
+                    .map_err(|err| err.with_operands(vec![left, right]))?;
+                env.stack.push(result);
             },
+            // dividing always results in a float, `div` is the integer division
             Command::Div => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(l / r));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while dividing!".into(), &call_stack, env));
-                }
+                let (left, right) = pop_operands(env, "dividing")?;
+                let result = match numbers(&left, &right) {
+                    Some(Numbers::Ints(l, r)) => StackSlot::Number(l as f64 / r as f64),
+                    Some(Numbers::Floats(l, r)) => StackSlot::Number(l / r),
+                    None => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "arithmetic is only supported for numbers", env)
+                        .with_operands(vec![left, right]))
+                };
+                env.stack.push(result);
+            },
+            Command::IntDiv => {
+                let (left, right) = pop_operands(env, "dividing")?;
+                let result = arithmetic(env, &left, &right, i64::checked_div, |l, r| (l / r).trunc())
+                    .map_err(|err| err.with_operands(vec![left, right]))?;
+                env.stack.push(result);
             },
             Command::Mod => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(l % r));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow in modulo operation!".into(), &call_stack, env));
-                }
+                let (left, right) = pop_operands(env, "taking the modulo")?;
+                let result = arithmetic(env, &left, &right, i64::checked_rem, |l, r| l % r)
+                    .map_err(|err| err.with_operands(vec![left, right]))?;
+                env.stack.push(result);
             },
-            Command::LT => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(if l < r { 1.0 } else { 0.0 }));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
-                }
-
+            command @ (Command::LT | Command::LE | Command::GT | Command::GE) => {
+                let command = command.clone();
+                let (left, right) = pop_operands(env, "comparing")?;
+                let ordering = match numbers(&left, &right) {
+                    Some(Numbers::Ints(l, r)) => Some(l.cmp(&r)),
+                    Some(Numbers::Floats(l, r)) => l.partial_cmp(&r),
+                    None => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "arithmetic is only supported for numbers", env)
+                        .with_operands(vec![left, right]))
+                };
+                let result = match (command, ordering) {
+                    (_, None) => false,
+                    (Command::LT, Some(ordering)) => ordering.is_lt(),
+                    (Command::LE, Some(ordering)) => ordering.is_le(),
+                    (Command::GT, Some(ordering)) => ordering.is_gt(),
+                    (_, Some(ordering)) => ordering.is_ge()
+                };
+                env.stack.push(StackSlot::Int(result as i64));
             },
-            Command::LE => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(if l <= r { 1.0 } else { 0.0 }));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
-                }
+            Command::EQ => {
+                let (left, right) = pop_operands(env, "comparing")?;
+                env.stack.push(StackSlot::Int(slots_equal(&left, &right) as i64));
             },
-            Command::GT => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(if l > r { 1.0 } else { 0.0 }));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
-                }
+            Command::NE => {
+                let (left, right) = pop_operands(env, "comparing")?;
+                env.stack.push(StackSlot::Int(!slots_equal(&left, &right) as i64));
             },
-            Command::GE => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    if let (StackSlot::Number(r), StackSlot::Number(l)) = (right, left) {
-                        env.stack.push(StackSlot::Number(if l >= r { 1.0 } else { 0.0 }));
-                    } else {
-                        return Err(RuntimeError::new("arithmetic is only supported for numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
-                }
+            Command::Not => match env.stack.pop() {
+                Some(StackSlot::Int(n)) => env.stack.push(StackSlot::Int((n == 0) as i64)),
+                Some(StackSlot::Number(n)) => env.stack.push(StackSlot::Int((n == 0.0) as i64)),
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "negation is only supported for Numbers", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while negating", env))
             },
-            Command::EQ => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    match (left, right) {
-                        (StackSlot::Number(r), StackSlot::Number(l)) =>
-                            env.stack.push(StackSlot::Number(if l == r { 1.0 } else { 0.0 })),
-                        (StackSlot::String(r), StackSlot::String(l)) =>
-                            env.stack.push(StackSlot::Number(if l == r { 1.0 } else { 0.0 })),
-                        (StackSlot::String(_), StackSlot::Number(_)) =>
-                            env.stack.push(StackSlot::Number(0.0)),
-                        (StackSlot::Number(_), StackSlot::String(_)) =>
-                            env.stack.push(StackSlot::Number(0.0)),
-                        _ => {
-                            env.stack.push(StackSlot::Number(0.0))
-                        }
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
+            command @ (Command::BitAnd | Command::BitOr | Command::BitXor | Command::Shl | Command::Shr) => {
+                let command = command.clone();
+                let (left, right) = pop_operands(env, "combining bits")?;
+                let (l, r) = match (&left, &right) {
+                    (StackSlot::Int(l), StackSlot::Int(r)) => (*l, *r),
+                    _ => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "bitwise operators need integers", env)
+                        .with_operands(vec![left, right]))
+                };
+                let shift = u32::try_from(r).ok();
+                let result = match command {
+                    Command::BitAnd => Some(l & r),
+                    Command::BitOr => Some(l | r),
+                    Command::BitXor => Some(l ^ r),
+                    Command::Shl => shift.and_then(|r| l.checked_shl(r)),
+                    _ => shift.and_then(|r| l.checked_shr(r))
+                };
+                match result {
+                    Some(n) => env.stack.push(StackSlot::Int(n)),
+                    None => return Err(RuntimeError::new(ErrorKind::Arithmetic, format!("can't shift by {} bits", r), env)
+                        .with_operands(vec![left, right]))
                 }
             },
-            Command::NE => {
-                if let (Some(right), Some(left)) = (env.stack.pop(), env.stack.pop()) {
-                    match (left, right) {
-                        (StackSlot::Number(r), StackSlot::Number(l)) =>
-                            env.stack.push(StackSlot::Number(if l != r { 1.0 } else { 0.0 })),
-                        (StackSlot::String(r), StackSlot::String(l)) =>
-                            env.stack.push(StackSlot::Number(if l != r { 1.0 } else { 0.0 })),
-                        (StackSlot::String(_), StackSlot::Number(_)) =>
-                            env.stack.push(StackSlot::Number(1.0)),
-                        (StackSlot::Number(_), StackSlot::String(_)) =>
-                            env.stack.push(StackSlot::Number(1.0)),
-                        _ => {
-                            env.stack.push(StackSlot::Number(1.0))
-                        }
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while comparing!".into(), &call_stack, env));
-                }
+            Command::BitNot => match env.stack.pop() {
+                Some(StackSlot::Int(n)) => env.stack.push(StackSlot::Int(!n)),
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "bitwise operators need integers", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while inverting bits", env))
             },
-            Command::Not => {
-                if let Some(ss) = env.stack.pop() {
-                    if let StackSlot::Number(n) = ss {
-                        env.stack.push(StackSlot::Number(
-                            if n == 0.0 {
-                                1.0
-                            } else {
-                                0.0
-                            }
-                        ));
-                    } else {
-                        return Err(RuntimeError::new("negation is only supported for Numbers".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("stack underflow while negating".into(), &call_stack, env));
-                }
+            Command::ToInt => match env.stack.pop() {
+                Some(StackSlot::Int(n)) => env.stack.push(StackSlot::Int(n)),
+                // `i64::MAX as f64` rounds up to 2^63, which is already too large
+                Some(StackSlot::Number(n)) if n.is_finite() && n >= i64::MIN as f64 && n < i64::MAX as f64 =>
+                    env.stack.push(StackSlot::Int(n.trunc() as i64)),
+                Some(StackSlot::Number(n)) => return Err(RuntimeError::new(ErrorKind::Arithmetic, format!("{} doesn't fit into an integer", n), env)
+                    .with_operands(vec![StackSlot::Number(n)])),
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "only numbers can be converted to integers", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while converting", env))
+            },
+            Command::ToFloat => match env.stack.pop() {
+                Some(StackSlot::Int(n)) => env.stack.push(StackSlot::Number(n as f64)),
+                Some(StackSlot::Number(n)) => env.stack.push(StackSlot::Number(n)),
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "only numbers can be converted to floats", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while converting", env))
             },
             Command::Dup => {
-                env.stack.push(env.stack.stack[env.stack.stack.len() - 1].clone());
+                let top = env.stack.stack.last().cloned()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while duplicating", env))?;
+                env.stack.push(top);
             },
             Command::Swap => {
                 if let (Some(top), Some(bot)) = (env.stack.pop(), env.stack.pop()) {
                     env.stack.push(top);
                     env.stack.push(bot);
                 } else {
-                    return Err(RuntimeError::new("stack underflow while swapping".into(), &call_stack, env));
+                    return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while swapping", env));
                 }
             },
             Command::Drop => {
                 env.stack.pop();
             },
             Command::Print => {
-                match env.stack.pop() {
-                    Some(slot) => match slot {
-                        StackSlot::Number(n) => print!("{}", n),
-                        StackSlot::String(s) => print!("{}", s.replace("\\n", "\n")),
-                        StackSlot::NamedReference(r, p) => print!("@{}+{}", r, p),
-                        StackSlot::AbsoluteReference(p) => print!("@{}", p)
-                    },
-                    None => println!("Stack underflow!")
+                let text = match env.stack.pop() {
+                    Some(slot) => slot.to_string(),
+                    None => String::from("Stack underflow!\n")
                 };
+                env.io.borrow_mut().print(&text);
             },
             Command::Getc => {
-                let mut stdout = std::io::stdout().into_raw_mode();
-                let mut input = [0; 1];
-                if let Ok(_) = stdin_reader.read_exact(&mut input) {
-                    env.stack.push(StackSlot::Number(input[0] as f64));
-                }
-                else {
-                    env.stack.push(StackSlot::Number(-1.0));
+                let input = env.io.borrow_mut().getc();
+                match input {
+                    Some(byte) => env.stack.push(StackSlot::Int(byte.into())),
+                    None => env.stack.push(StackSlot::Int(-1))
                 }
             },
             Command::ArrowPut => {
-                let value: Command;
-                match env.stack.pop() {
-                    Some(StackSlot::Number(n)) => value = Command::Pushn(n),
-                    Some(StackSlot::String(s)) => value = Command::Pushs(s),
-                    Some(StackSlot::NamedReference(r, offset)) => value = Command::NamedReference(String::from("@") + r.as_ref(), offset),
-                    Some(StackSlot::AbsoluteReference(position)) => value = Command::AbsoluteReference(position),
-                    None => return Err(RuntimeError::new("stack underflow for arrow expression".into(), &call_stack, env))
-                };
+                let value = env.stack.pop()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow for arrow expression", env))?
+                    .into_cell();
 
-                if let Command::NamedReference(name, offset) = env.program[env.idx + 1].clone() {
+                if let Some(Command::NamedReference(name, offset)) = env.program.get(env.idx + 1).cloned() {
                     if let Ok(pos) = Environment::resolve_reference(&env.definitions, name.split('@').collect::<Vec<&str>>()[1].into()) {
-                        env.program[pos + 1 + offset] = value.clone();
+                        match env.program.get_mut(pos + 1 + offset) {
+                            Some(cell) => *cell = value,
+                            None => return Err(RuntimeError::new(ErrorKind::BadReference, format!("`{}` points past the end of the program", name), env))
+                        }
+                        env.written(pos + 1 + offset);
                         env.idx += 1;
                     }
                     else {
-                        return Err(RuntimeError::new(format!("no such symbol: `{}`", name), &call_stack, env));
+                        return Err(RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env));
                     }
                 } else {
-                    return Err(RuntimeError::new("reference required for arrow put".into(), &call_stack, env));
+                    return Err(RuntimeError::new(ErrorKind::BadReference, "reference required for arrow put", env));
                 }
             },
             Command::Put => {
                 let pos = match env.stack.pop() {
                     Some(StackSlot::NamedReference(name, offset)) => {
                         let base = env.definitions.get(&name)
-                            .ok_or_else(|| RuntimeError::new(format!("no such symbol: `{}`", name), &call_stack, env))?;
+                            .ok_or_else(|| RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env))?;
 
                         base + offset
                     },
                     Some(StackSlot::AbsoluteReference(position)) => position,
-                    _ => return Err(RuntimeError::new("reference required for put".into(), &call_stack, env))
+                    Some(StackSlot::HeapReference(block, offset)) => {
+                        let value = env.stack.pop()
+                            .ok_or_else(|| RuntimeError::new(ErrorKind::StackUnderflow, "value required for put", env))?;
+                        *heap_cell(env, block, offset)? = value;
+                        env.idx += 1;
+                        continue;
+                    },
+                    other => return Err(RuntimeError::new(ErrorKind::BadReference, "reference required for put", env)
+                        .with_operands(other.into_iter().collect()))
                 };
 
-                match env.stack.pop() {
-                    Some(StackSlot::Number(n)) => env.program[pos + 1] = Command::Pushn(n),
-                    Some(StackSlot::String(s)) => env.program[pos + 1] = Command::Pushs(s),
-                    Some(StackSlot::NamedReference(r, offset)) => env.program[pos + 1] = Command::NamedReference(String::from("@") + r.as_ref(), offset),
-                    Some(StackSlot::AbsoluteReference(position)) => env.program[pos + 1] = Command::AbsoluteReference(position),
-                    None => {
-                        return Err(RuntimeError::new("value required for put".into(), &call_stack, env));
-                    }
-                };
+                let value = env.stack.pop()
+                    .ok_or_else(|| RuntimeError::new(ErrorKind::StackUnderflow, "value required for put", env))?
+                    .into_cell();
+                match env.program.get_mut(pos + 1) {
+                    Some(cell) => *cell = value,
+                    None => return Err(RuntimeError::new(ErrorKind::BadReference, "the reference points past the end of the program", env))
+                }
+                env.written(pos + 1);
             },
             Command::Get => {
                 let pos = match env.stack.pop() {
                     Some(StackSlot::NamedReference(name, offset)) => {
                         let base = env.definitions.get(&name)
-                            .ok_or_else(|| RuntimeError::new(format!("no such symbol: `{}`", name), &call_stack, env))?;
+                            .ok_or_else(|| RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env))?;
 
                         base + offset
                     },
                     Some(StackSlot::AbsoluteReference(position)) => position,
-                    _ => return Err(RuntimeError::new("reference required for get".into(), &call_stack, env))
+                    Some(StackSlot::HeapReference(block, offset)) => {
+                        let value = heap_cell(env, block, offset)?.clone();
+                        env.stack.push(value);
+                        env.idx += 1;
+                        continue;
+                    },
+                    other => return Err(RuntimeError::new(ErrorKind::BadReference, "reference required for get", env)
+                        .with_operands(other.into_iter().collect()))
                 };
 
-                match env.program.get(pos + 1) {
-                    Some(Command::Pushn(n)) => env.stack.push(StackSlot::Number(*n)),
-                    Some(Command::Pushs(s)) => env.stack.push(StackSlot::String(s.clone())),
-                    Some(Command::NamedReference(s, offset)) => env.stack.push(StackSlot::NamedReference(String::from(&s[1..]), *offset)),
-                    Some(Command::AbsoluteReference(position)) => env.stack.push(StackSlot::AbsoluteReference(*position)),
-                    _ => return Err(RuntimeError::new("value required for get".into(), &call_stack, env))
+                match env.program.get(pos + 1).and_then(Command::value) {
+                    Some(value) => env.stack.push(value),
+                    None => return Err(RuntimeError::new(ErrorKind::BadReference, "value required for get", env))
                 }
             }
             Command::Pull => {
-                if let StackSlot::Number(n) = env.stack.pop().unwrap() {
-                    if n.is_sign_positive() && n.floor() == n {
-                        env.stack.push(env.stack.stack[n as usize].clone())
-                    } else if n.is_sign_negative() && n.floor() == n {
-                        env.stack.push(env.stack.stack[(env.stack.stack.len() as isize + n as isize) as usize].clone())
-                    } else {
-                        return Err(RuntimeError::new("expected integer for pull".into(), &call_stack, env));
-                    }
-                } else {
-                    return Err(RuntimeError::new("expected integer for pull".into(), &call_stack, env));
+                let slot = env.stack.pop();
+                match slot.as_ref().map(|slot| (slot, integer(slot))) {
+                    Some((_, Some(n))) => {
+                        let position = if n >= 0 {
+                            n as isize
+                        } else {
+                            env.stack.stack.len() as isize + n as isize
+                        };
+
+                        let item = usize::try_from(position).ok()
+                            .and_then(|p| env.stack.stack.get(p)).cloned()
+                            .ok_or_else(|| RuntimeError::new(ErrorKind::StackUnderflow, format!("can't pull item {} from the stack", n), env))?;
+                        env.stack.push(item);
+                    },
+                    Some((other, None)) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "expected integer for pull", env)
+                        .with_operands(vec![other.clone()])),
+                    None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while pulling", env))
                 }
             },
             Command::NamedReference(s, offset) => {
@@ -666,48 +1496,94 @@ fn run(env: &mut Environment) -> Result<(), RuntimeError> {
                     let absolute_addess = env.definitions[name] + offset;
                     env.program[env.idx] = Command::AbsoluteReference(absolute_addess);
                 } else {
-                    return Err(RuntimeError::new(format!("no such symbol: `{}`", name), &call_stack, env));
+                    return Err(RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env));
                 }
             },
             Command::AbsoluteReference(position) => {
                 env.stack.push(StackSlot::AbsoluteReference(*position));
             },
+            Command::HeapReference(block, offset) => env.stack.push(StackSlot::HeapReference(*block, *offset)),
+            Command::Closure(position, captured) => env.stack.push(StackSlot::Closure(*position, captured.clone())),
             Command::AddressOf => {
-                if let StackSlot::String(name) = env.stack.pop().unwrap() {
-                    let s: &str = name.as_ref();
-                    if env.definitions.contains_key(s) {
-                        env.stack.push(StackSlot::NamedReference(String::from(s), 0));
-                    } else {
-                        return Err(RuntimeError::new(format!("no such symbol: `{}`", s), &call_stack, env));
-
-                    }
-                } else {
-                    return Err(RuntimeError::new("string required".into(), &call_stack, env));
+                match env.stack.pop() {
+                    Some(StackSlot::String(name)) => {
+                        if env.definitions.contains_key(&name) {
+                            env.stack.push(StackSlot::NamedReference(name, 0));
+                        } else {
+                            return Err(RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env));
+                        }
+                    },
+                    Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "string required", env)
+                        .with_operands(vec![other])),
+                    None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while looking up an address", env))
                 }
             },
             Command::Lambda(skip) => {
                 env.stack.push(StackSlot::AbsoluteReference(env.idx));
                 env.idx += *skip;
             }
-            Command::PrintStack => println!("{:?}", env.stack.stack),
-            Command::Placeholder => {
-                return Err(RuntimeError::new("encountered placeholder".into(), &call_stack, env));
-            },
-            Command::Bytes => if let StackSlot::String(s) = env.stack.pop().unwrap() {
-                    for byte in s.as_bytes() {
-                        env.stack.push(StackSlot::Number(*byte as f64));
-                    }
+            Command::Capture(names) => {
+                let position = env.idx + 1;
+                let mut captured = Vec::new();
+                for name in names {
+                    let cell = env.definitions.get(&name[1..])
+                        .ok_or_else(|| RuntimeError::new(ErrorKind::UnknownSymbol, format!("no such symbol: `{}`", name), env))? + 1;
+                    captured.push((cell, env.program[cell].clone()));
                 }
-                else {
-                    return Err(RuntimeError::new("needs a string to convert into number list".into(), &call_stack, env));
-                },
-            Command::Sleep => if let StackSlot::Number(n) = env.stack.pop().unwrap() {
-                    thread::sleep(time::Duration::from_secs_f64(n));
+                env.stack.push(StackSlot::Closure(position, captured.into()));
+
+                match env.program[position] {
+                    Command::Lambda(skip) => env.idx = position + skip,
+                    _ => unreachable!("`Capture` is always followed by its `Lambda`")
                 }
-                else {
-                    return Err(RuntimeError::new("sleep needs a number input".into(), &call_stack, env));
+            }
+            Command::SkipIfZero(skip) | Command::SkipIfNotZero(skip) => {
+                let skip = *skip;
+                let if_zero = matches!(env.program[env.idx], Command::SkipIfZero(_));
+                match env.stack.pop() {
+                    Some(StackSlot::Number(n)) => if (n == 0.0) == if_zero {
+                        env.idx += skip;
+                    },
+                    Some(StackSlot::Int(n)) => if (n == 0) == if_zero {
+                        env.idx += skip;
+                    },
+                    Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "logical operators need numbers", env)
+                        .with_operands(vec![other])),
+                    None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow in a logical operator", env))
                 }
-            _ => {}
+            }
+            Command::PrintStack => {
+                let text = format!("{:?}\n", env.stack.stack);
+                env.io.borrow_mut().print(&text);
+            },
+            // only the debugger stops here
+            Command::Breakpoint => {},
+            Command::Placeholder => {
+                return Err(RuntimeError::new(ErrorKind::InvalidOperation, "encountered placeholder", env));
+            },
+            Command::Bytes => match env.stack.pop() {
+                Some(StackSlot::String(s)) => for byte in s.as_bytes() {
+                    env.stack.push(StackSlot::Int((*byte).into()));
+                },
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "needs a string to convert into number list", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while converting to bytes", env))
+            },
+            Command::Sleep => match env.stack.pop() {
+                Some(StackSlot::Number(n)) if n >= 0.0 => {
+                    let duration = time::Duration::try_from_secs_f64(n)
+                        .map_err(|_| RuntimeError::new(ErrorKind::InvalidOperation, format!("can't sleep for {} seconds", n), env))?;
+                    env.io.borrow_mut().sleep(duration);
+                },
+                Some(StackSlot::Int(n)) if n >= 0 => env.io.borrow_mut().sleep(time::Duration::from_secs(n as u64)),
+                Some(other) => return Err(RuntimeError::new(ErrorKind::TypeMismatch, "sleep needs a positive number input", env)
+                    .with_operands(vec![other])),
+                None => return Err(RuntimeError::new(ErrorKind::StackUnderflow, "stack underflow while sleeping", env))
+            },
+            Command::Native(index) => {
+                let native = env.natives[*index].func.clone();
+                native(env)?;
+            }
         }
 
         env.idx += 1;
@@ -715,15 +1591,124 @@ fn run(env: &mut Environment) -> Result<(), RuntimeError> {
     Ok(())
 }
 
+/// include a file behind the current instruction unless it was included before
+fn include(env: &mut Environment, filename: &str) -> Result<(), RuntimeError> {
+    // bytecode names the files it already contains by their key
+    if env.includes.iter().any(|key| key == filename) {
+        return Ok(());
+    }
+
+    let found = find_include(env, env.idx, filename)
+        .map_err(|err| RuntimeError::new(ErrorKind::IncludeFailed, format!("unable to read include file: {}", err), env))?;
+    if env.includes.contains(&found.key) {
+        return Ok(());
+    }
+
+    let content = found.read()
+        .map_err(|err| RuntimeError::new(ErrorKind::IncludeFailed, format!("unable to read include file: {}", err), env))?;
+    env.includes.push(found.key);
+
+    load(env, &found.name, &content, env.idx + 1).map_err(|err| match err {
+        Error::Syntax(err) => RuntimeError::new(ErrorKind::Syntax, format!("unable to parse `{}`: {}", filename, err), env),
+        Error::Runtime(err) => err
+    })
+}
+
+/// Add the names of `module` to the definitions, prefixed with `alias` unless it is empty.
+///
+/// A module with an `export` list only shows the listed names and whatever is declared inside of
+/// them. Names that are already defined are kept, so `open` never replaces a definition of the
+/// script or of a module that was opened before.
+fn link_module(env: &mut Environment, alias: &str, module: &str) {
+    let exports: Vec<&str> = env.program.iter()
+        .filter_map(|command| match command {
+            Command::Export(names) => Some(names),
+            _ => None
+        })
+        .flatten()
+        .filter_map(|name| name[1..].rsplit_once("::"))
+        .filter(|(owner, _)| *owner == module)
+        .map(|(_, name)| name)
+        .collect();
+
+    let prefix = format!("{}::", module);
+    let mut linked = Vec::new();
+    for (name, position) in &env.definitions {
+        let Some(rest) = name.strip_prefix(&prefix) else { continue };
+        let exported = exports.is_empty() || exports.contains(&rest.split("::").next().unwrap_or_default());
+        let link = if alias.is_empty() { rest.to_string() } else { format!("{}::{}", alias, rest) };
+        if exported && !env.definitions.contains_key(&link) {
+            linked.push((link, name.clone(), *position));
+        }
+    }
+
+    for (link, name, position) in linked {
+        env.definitions.insert(link.clone(), position);
+        env.links.insert(link, name);
+    }
+}
+
+/// Point the names added by `link_module` at the definitions they stand for again.
+///
+/// The parser moves definitions around, so this runs after every parse. The parser starts without
+/// the links, a name that it defined itself isn't a link anymore.
+fn relink(env: &mut Environment) {
+    let definitions = &env.definitions;
+    env.links.retain(|link, _| !definitions.contains_key(link));
+    for (link, name) in &env.links {
+        if let Some(&position) = env.definitions.get(name) {
+            env.definitions.insert(link.clone(), position);
+        }
+    }
+}
+
+/// find the file for the include at `idx`, relative to the file that include is written in
+fn find_include(env: &Environment, idx: usize, filename: &str) -> std::io::Result<library::Found> {
+    let from = env.source.get(idx)
+        .and_then(|source| env.files.get(source.span().file))
+        .map_or("", |file| file.name.as_str());
+    library::find(filename, from, &env.search_path)
+}
+
+/// lex `text` and insert it into the program at `at`
+fn load(env: &mut Environment, name: &str, text: &str, at: usize) -> Result<(), Error> {
+    let file = env.add_file(name, text);
+    let code = lexer(env, file)?;
+    Ok(splice(env, at, code)?)
+}
+
+/// Insert freshly lexed code into the program after `at` and parse it.
+///
+/// If the new code doesn't parse the program is restored to the state before.
+fn splice(env: &mut Environment, at: usize, code: Environment) -> Result<(), SyntaxError> {
+    let definitions = env.definitions.clone();
+    let prefix = env.prefix.clone();
+    let level = env.level;
+    let idx = env.idx;
+    let count = code.program.len();
+
+    env.program.splice(at..at, code.program);
+    env.source.splice(at..at, code.source);
+
+    let result = parser(env);
+    if result.is_err() {
+        env.program.drain(at..at + count);
+        env.source.drain(at..at + count);
+        env.definitions = definitions;
+        env.prefix = prefix;
+        env.level = level;
+        env.idx = idx;
+    }
 
-fn ends_with_whitespace(string: &str) -> bool {
-    let last = string.chars().last();
-    last == Some('\n') || last == Some('\t') || last == Some(' ')
+    result
 }
 
-fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
+fn parser(env: &mut Environment) -> Result<(), SyntaxError> {
     let mut define_stack: Vec<usize> = vec![];
     let env_start_idx = env.idx;
+    for link in env.links.keys() {
+        env.definitions.remove(link);
+    }
 
     env.idx = 0;
     while env.idx < env.program.len() {
@@ -731,20 +1716,20 @@ fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
             Command::Define(v, _) => {
                 define_stack.push(env.idx);
                 env.level += 1;
+                let label = env.idx.checked_sub(1).map(|i| env.program[i].clone());
                 if let Visibility::Public = v {
-                    if let Command::Pushs(string) = env.program[env.idx - 1].clone() {
+                    if let Some(Command::Pushs(string)) = label {
                         env.define_new(string);
                     }
                     else {
-                        return Err(RuntimeError::new("public define needs a label".into(), &vec![], &env));
+                        return Err(env.syntax_error("definition needs a name in front of it"));
                     }
                 } else if env.execute {
                     if let Some(StackSlot::String(string)) = env.stack.pop() {
-                        println!("priv");
                         env.define_new(string);
                     }
                     else {
-                        return Err(RuntimeError::new("string required for private define".into(), &vec![], &env));
+                        return Err(env.syntax_error("`priv` needs the name of the definition on the stack"));
                     }
                 }
             },
@@ -753,16 +1738,49 @@ fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
                 env.level += 1;
                 define_stack.push(env.idx);
             },
-            Command::EndDefine => {
-                let start_idx = define_stack.pop().unwrap();
-                env.level -= 1;
-                env.prefix.pop();
+            Command::Try(..) => {
+                env.program[env.idx] = Command::Try(0, 0, 0);
+                define_stack.push(env.idx);
+            },
+            Command::Catch(_) | Command::Finally => {
+                let start_idx = define_stack.last().copied()
+                    .filter(|&start| matches!(env.program[start], Command::Try(..)))
+                    .ok_or_else(|| env.syntax_error("`catch` and `finally` can only be used inside of a `try`"))?;
+                let offset = env.idx - start_idx;
+                match (&env.program[env.idx], &env.program[start_idx]) {
+                    (Command::Catch(_), Command::Try(0, 0, _)) => env.program[start_idx] = Command::Try(offset, 0, 0),
+                    (Command::Finally, Command::Try(catch, 0, _)) => env.program[start_idx] = Command::Try(*catch, offset, 0),
+                    (Command::Catch(_), _) => return Err(env.syntax_error("a `try` has at most one `catch`, it comes before `finally`")),
+                    _ => return Err(env.syntax_error("a `try` has at most one `finally`"))
+                }
+            },
+            Command::EndDefine | Command::EndTry => {
+                let start_idx = define_stack.pop()
+                    .ok_or_else(|| env.syntax_error("unmatched end of definition, there is no open `is`, `{`, `lambda` or `try`"))?;
                 match &env.program[start_idx] {
                     Command::Define(v, _) => {
                         env.program[start_idx] = Command::Define(v.clone(), env.idx - start_idx);
+                        env.level -= 1;
+                        env.prefix.pop();
                     }
                     Command::Lambda(_) => {
                         env.program[start_idx] = Command::Lambda(env.idx - start_idx);
+                        env.level -= 1;
+                        env.prefix.pop();
+                    }
+                    &Command::Try(catch, finally, _) => {
+                        if catch == 0 && finally == 0 {
+                            env.idx = start_idx;
+                            return Err(env.syntax_error("`try` needs a `catch` or a `finally` block"));
+                        }
+                        let end = env.idx - start_idx;
+                        env.program[start_idx] = Command::Try(catch, finally, end);
+                        if catch != 0 {
+                            // a successful `try` block continues after the handler
+                            let after = if finally != 0 { finally } else { end };
+                            env.program[start_idx + catch] = Command::Catch(after - catch);
+                        }
+                        env.program[env.idx] = Command::EndTry;
                     }
                     _ => {}
                 }
@@ -772,10 +1790,15 @@ fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
         env.idx += 1;
     }
 
+    if let Some(start_idx) = define_stack.pop() {
+        env.idx = start_idx;
+        return Err(env.syntax_error("definition is never closed with `in` or `}`"));
+    }
+
     env.idx = 0;
     while env.idx < env.program.len() {
         match &env.program[env.idx] {
-            Command::Define(v, _) => {
+            Command::Define(_, _) => {
                 if let Command::Pushs(string) = env.program[env.idx - 1].clone() {
                     env.prefix.push(string);
                 }
@@ -786,23 +1809,36 @@ fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
             Command::EndDefine => {
                 env.prefix.pop();
             }
-            Command::NamedReference(name, offset) => {
-                // if the address starts with `::` the scope will be inferred
-                if name.starts_with("@::") {
-                    let mut full_name = None;
-                    for i in 0..env.prefix.len() {
-                        let test_name = format!("@{}{}", env.prefix[0..env.prefix.len() - i].join("::"), &name[1..]);
-                        if env.definitions.contains_key(&test_name[1..]) {
-                            full_name = Some(test_name);
-                            break;
-                        }
-                    }
-                    env.program[env.idx] = Command::NamedReference(
-                        full_name
-                            .ok_or_else(|| RuntimeError::new(format!("no such symbol: `{}`", name), &vec![], env))?,
-                        *offset
-                    );
+            // if the address starts with `::` the scope will be inferred
+            Command::NamedReference(name, offset) if name.starts_with("@::") => {
+                env.program[env.idx] = Command::NamedReference(infer_scope(env, name)?, *offset);
+            },
+            // the captured locals are looked up from outside of the lambda
+            Command::Capture(names) => {
+                let names = names.iter()
+                    .map(|name| if name.starts_with("@::") { infer_scope(env, name) } else { Ok(name.clone()) })
+                    .collect::<Result<Vec<String>, SyntaxError>>()?;
+                env.program[env.idx] = Command::Capture(names);
+            },
+            // exports belong to the definition they are declared in
+            Command::Export(names) => {
+                if env.prefix.is_empty() {
+                    return Err(env.syntax_error("`export` has to be inside of the module it lists the names of"));
                 }
+                let names = names.iter()
+                    .map(|name| match name.strip_prefix("@::") {
+                        Some(name) => {
+                            let full_name = format!("{}::{}", env.prefix.join("::"), name);
+                            if env.definitions.contains_key(&full_name) {
+                                Ok(format!("@{}", full_name))
+                            } else {
+                                Err(env.syntax_error(format!("`{}` exports `{}` but doesn't define it", env.prefix.join("::"), name)))
+                            }
+                        },
+                        None => Ok(name.clone())
+                    })
+                    .collect::<Result<Vec<String>, SyntaxError>>()?;
+                env.program[env.idx] = Command::Export(names);
             },
             _ => {}
         }
@@ -810,32 +1846,159 @@ fn parser(env: &mut Environment) -> Result<(), RuntimeError> {
     }
 
     env.idx = env_start_idx;
+    index_locals(env);
+    relink(env);
 
     Ok(())
 }
 
-fn lexer(program: String) -> Environment {
+/// the full name of a `@::name` reference, the innermost enclosing definition that has a `name` wins
+fn infer_scope(env: &Environment, name: &str) -> Result<String, SyntaxError> {
+    (0..env.prefix.len())
+        .map(|i| format!("@{}{}", env.prefix[0..env.prefix.len() - i].join("::"), &name[1..]))
+        .find(|full_name| env.definitions.contains_key(&full_name[1..]))
+        .ok_or_else(|| env.syntax_error(format!("no such symbol: `{}`", name)))
+}
+
+/// Collect the locals of every definition and lambda, the cells of the definitions declared
+/// directly inside of them with nothing but `_` and `_N`, parameters are declared like that too.
+///
+/// The tokens decide and not the cells, those may hold values written while the program ran.
+fn index_locals(env: &mut Environment) {
+    env.locals.clear();
+    // the open definitions and lambdas, and whether something was declared inside of them
+    let mut open: Vec<(usize, bool)> = Vec::new();
+
+    for (idx, command) in env.program.iter().enumerate() {
+        match command {
+            Command::Define(..) | Command::Lambda(_) => {
+                if let Some(parent) = open.last_mut() {
+                    parent.1 = true;
+                }
+                open.push((idx, false));
+            },
+            Command::EndDefine => {
+                let (start, nested) = match open.pop() {
+                    Some(definition) => definition,
+                    None => continue
+                };
+                let placeholders = idx > start + 1 && env.source[start + 1..idx].iter().all(|source| match source {
+                    SourceReference::Visible(text, _) => text.starts_with('_') && text[1..].bytes().all(|b| b.is_ascii_digit()),
+                    // the cells of a parameter and the ones `_N` adds in front of its token
+                    SourceReference::Invisible(_) => true
+                });
+                if let (Command::Define(..), false, true, Some(&(owner, _))) = (&env.program[start], nested, placeholders, open.last()) {
+                    env.locals.entry(owner).or_default().extend(start + 1..idx);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// the text of a `"quoted"` token
+fn string_literal(token: &str) -> String {
+    token[1..token.len() - 1]
+        .replace("\\\"", "\"")
+        .replace("\\n", "\n")
+}
+
+/// the commands for a reference token, each `$`, `!` and `?` at the end adds a `get`, `jump`
+/// or `jump?`
+fn reference(token: &str, span: &Span) -> Vec<(Command, SourceReference)> {
+    let jumps: Vec<Command> = token.chars().rev()
+        .map_while(|c| match c {
+            '$' => Some(Command::Get),
+            '!' => Some(Command::Jmp),
+            '?' => Some(Command::JmpIf),
+            _ => None
+        }).collect();
+
+    let mut commands = vec![(
+        Command::NamedReference(String::from(&token[0..token.len() - jumps.len()]), 0),
+        SourceReference::Visible(token.into(), span.clone())
+    )];
+    commands.extend(jumps.into_iter().rev().map(|jump| (jump, SourceReference::Invisible(span.clone()))));
+    commands
+}
+
+/// whether the token at `idx` is the name of a definition, in front of `is`, `priv`, `{`, a
+/// parameter list or `addr`
+fn names_definition(prog: &[tokenizer::Token], idx: usize) -> bool {
+    prog.get(idx + 1).is_some_and(|next| matches!(next.text.as_str(), "is" | "priv" | "{" | "addr"))
+        || parameter_list(prog, idx + 1).is_some()
+}
+
+/// The names in `lambda [ a b ]` if the token at `idx` is such a `lambda`, together with the
+/// position of the `]`. A `[` that is followed by anything but names starts a list literal instead.
+fn capture_list(prog: &[tokenizer::Token], idx: usize) -> Option<(Vec<String>, usize)> {
+    if prog.get(idx + 1)?.text != "[" {
+        return None;
+    }
+
+    let mut names = Vec::new();
+    let mut close = idx + 2;
+    while prog.get(close)?.text != "]" {
+        if !is_name(&prog[close].text) {
+            return None;
+        }
+        names.push(prog[close].text.clone());
+        close += 1;
+    }
+
+    if names.is_empty() { None } else { Some((names, close)) }
+}
+
+/// whether a token can name a parameter or a captured local
+fn is_name(token: &str) -> bool {
+    token.starts_with(char::is_alphabetic) && !token.contains(['@', '"', '$', '!', '?'])
+}
+
+/// The names in `name ( a b ) is` if the token at `idx` is the `(` of such a list, together with
+/// the position of its `)`.
+fn parameter_list(prog: &[tokenizer::Token], idx: usize) -> Option<(Vec<String>, usize)> {
+    if idx == 0 || prog.get(idx)?.text != "(" {
+        return None;
+    }
+
+    let mut names = Vec::new();
+    let mut close = idx + 1;
+    while prog.get(close)?.text != ")" {
+        if !is_name(&prog[close].text) {
+            return None;
+        }
+        names.push(prog[close].text.clone());
+        close += 1;
+    }
+
+    if prog.get(close + 1)?.text == "is" { Some((names, close)) } else { None }
+}
+
+fn lexer(env: &Environment, file: usize) -> Result<Environment, Error> {
+    let natives: HashMap<&str, usize> = env.natives.iter().enumerate()
+        .map(|(i, native)| (native.name.as_str(), i))
+        .collect();
     let mut commands: Vec<Command> = Vec::new();
     let mut source: Vec<SourceReference> = Vec::new();
     let mut idx = 0;
-    let comment = Regex::new(r"(?m)//.*$").unwrap();
-
-    let mut preprocessed = program;
-    preprocessed = preprocessed.replace("(", " ( ");
-    preprocessed = preprocessed.replace(")", " ) ");
-    preprocessed = comment.replace_all(preprocessed.as_ref(), "").to_string();
 
-    let prog: Vec<&str> = preprocessed
-        .split_whitespace()
-        .collect();
+    let prog = tokenizer::tokenize(&env.files[file].text, file)
+        .map_err(|span| SyntaxError::new("unterminated string", env.span_location(&span)))?;
 
     while idx < prog.len() {
+        let span = prog[idx].span.clone();
         let next: Command =
-            match prog[idx] {
+            match prog[idx].text.as_str() {
                 "include" =>
                     Command::Include,
+                "[" =>
+                    Command::ListStart,
+                "]" =>
+                    Command::ListEnd,
                 "STACK" =>
                     Command::PrintStack,
+                "breakpoint" =>
+                    Command::Breakpoint,
                 "getc" =>
                     Command::Getc,
                 "{" =>
@@ -872,8 +2035,45 @@ fn lexer(program: String) -> Environment {
                     Command::Pull,
                 "->" =>
                     Command::ArrowPut,
+                // `lambda [ a b ]` captures the locals `a` and `b`
+                "lambda" if capture_list(&prog, idx).is_some() => {
+                    let (names, close) = capture_list(&prog, idx).unwrap();
+                    commands.push(Command::Capture(names.iter().map(|name| format!("@::{}", name)).collect()));
+                    source.push(SourceReference::Visible(prog[idx].text.clone(), span.clone()));
+                    commands.push(Command::Lambda(0));
+                    source.push(SourceReference::Invisible(span.clone()));
+                    idx = close;
+                    Command::Nop
+                },
                 "lambda" =>
                     Command::Lambda(0),
+                "import" => match (prog.get(idx + 1), prog.get(idx + 2)) {
+                    (Some(keyword), Some(alias)) if keyword.text == "as" && is_name(&alias.text) => {
+                        commands.push(Command::Import(alias.text.clone()));
+                        source.push(SourceReference::Visible(prog[idx].text.clone(), span.clone()));
+                        idx += 2;
+                        Command::Nop
+                    },
+                    _ => return Err(SyntaxError::new("`import` is followed by `as` and the name to use for the module",
+                        env.span_location(&span)).into())
+                },
+                "open" =>
+                    Command::Open,
+                // `export [ a b ]` lists the names a module shows to `import` and `open`
+                "export" => {
+                    let (names, close) = capture_list(&prog, idx)
+                        .ok_or_else(|| SyntaxError::new("`export` needs a list of names like `export [ a b ]`", env.span_location(&span)))?;
+                    commands.push(Command::Export(names.iter().map(|name| format!("@::{}", name)).collect()));
+                    source.push(SourceReference::Visible(prog[idx].text.clone(), span.clone()));
+                    idx = close;
+                    Command::Nop
+                },
+                "try" =>
+                    Command::Try(0, 0, 0),
+                "catch" =>
+                    Command::Catch(0),
+                "finally" =>
+                    Command::Finally,
                 "__bytes" =>
                     Command::Bytes,
                 "+" =>
@@ -886,6 +2086,24 @@ fn lexer(program: String) -> Environment {
                     Command::Div,
                 "%" =>
                     Command::Mod,
+                "div" =>
+                    Command::IntDiv,
+                "bit-and" =>
+                    Command::BitAnd,
+                "bit-or" =>
+                    Command::BitOr,
+                "bit-xor" =>
+                    Command::BitXor,
+                "bit-not" =>
+                    Command::BitNot,
+                "shl" =>
+                    Command::Shl,
+                "shr" =>
+                    Command::Shr,
+                "int" =>
+                    Command::ToInt,
+                "float" =>
+                    Command::ToFloat,
                 "<" =>
                     Command::LT,
                 "<=" =>
@@ -906,57 +2124,86 @@ fn lexer(program: String) -> Environment {
                     Command::Sleep,
                 "_" => 
                     Command::Return,
-                s if s == "\\space" => {
+                // a native doesn't stop scripts from using its name for a definition
+                s if natives.contains_key(s) && !names_definition(&prog, idx) =>
+                    Command::Native(natives[s]),
+                "\\space" => {
                     Command::Pushs(String::from(" "))
                 },
-                s if String::from(s).starts_with('\"') => {
-                    let mut string = String::from(s);
-
-                    while !prog[idx].ends_with('\"') || prog[idx].ends_with("\\\"") {
-                        idx += 1;
-                        string.push(' ');
-                        string.push_str(prog[idx].as_ref());
-                    }
-
-                    Command::Pushs(String::from(&string)
-                        .get(1..string.len() - 1).expect(&format!("failed parsing string: {}", s))
-                        .replace("\\\"", "\"")
-                        .replace("\\n", "\n"))
-                },
+                s if s.starts_with('\"') =>
+                    Command::Pushs(string_literal(s)),
                 s if s.starts_with('@') => {
-                    let jumps: Vec<Command> = s.chars().rev()
-                        .map_while(|c| match c {
-                            '$' => Some(Command::Get),
-                            '!' => Some(Command::Jmp),
-                            '?' => Some(Command::JmpIf),
-                            _ => None
-                        }).collect();
-
-                    commands.push(Command::NamedReference(String::from(&s[0..s.len() - jumps.len()]), 0));
-                    source.push(SourceReference::Visible(s.into()));
-                    source.append(&mut (0..jumps.len()).map(|_| SourceReference::Invisible).collect());
-                    commands.append(&mut jumps.into_iter().rev().collect());
+                    for (command, source_ref) in reference(s, &span) {
+                        commands.push(command);
+                        source.push(source_ref);
+                    }
                     Command::Nop
                 },
                 s if s.starts_with('_') => {
-                    let n = (&s[1..]).parse::<usize>();
+                    let n = s[1..].parse::<usize>();
+                    if let Ok(0) = n {
+                        return Err(SyntaxError::new("can't reserve zero cells", env.span_location(&span)).into());
+                    }
+                    // check the size before the cells exist, the limits are only looked at while running
+                    let max = env.limits.program_size.unwrap_or(MAX_RESERVED_CELLS).min(MAX_RESERVED_CELLS);
+                    let available = max.saturating_sub(env.program.len() + commands.len());
+                    let too_long = n.is_err() && s[1..].bytes().all(|b| b.is_ascii_digit());
+                    if too_long || n.as_ref().is_ok_and(|&v| v > available) {
+                        return Err(RuntimeError::new(ErrorKind::LimitExceeded,
+                            format!("limit exceeded: `{}` reserves more cells than the {} the program still has room for", s, available), env)
+                            .at(s, env.span_location(&span)).into());
+                    }
                     if let Ok(v) = n {
                         for _i in 0..v-1 {
                             commands.push(Command::Return);
-                            source.push(SourceReference::Invisible);
+                            source.push(SourceReference::Invisible(span.clone()));
                         }
                     }
                     Command::Return
                 },
+                s if s.parse::<i64>().is_ok() =>
+                    Command::Pushi(s.parse::<i64>().unwrap()),
                 s if s.parse::<f64>().is_ok() =>
                     Command::Pushn(s.parse::<f64>().unwrap()),
-                s if String::from(s).starts_with('(') => {
-                    let stuff = arithparser::parse(&prog, &mut idx);
-                    for com in stuff[..stuff.len() - 1].iter() {
-                        commands.push(com.clone());
-                        source.push(SourceReference::Invisible);
+                ")" =>
+                    return Err(SyntaxError::new("unmatched `)`", env.span_location(&span)).into()),
+                // `name ( a b ) is` becomes `name is a is _ in b is _ in -> @::b -> @::a`
+                "(" if parameter_list(&prog, idx).is_some() => {
+                    let (names, close) = parameter_list(&prog, idx).unwrap();
+                    if let Some(twice) = names.iter().enumerate().find(|(i, name)| names[..*i].contains(name)) {
+                        return Err(SyntaxError::new(format!("parameter `{}` is declared twice", twice.1),
+                            env.span_location(&prog[idx + 1 + twice.0].span)).into());
+                    }
+
+                    commands.push(Command::Define(Visibility::Public, 0));
+                    source.push(SourceReference::Visible(prog[close + 1].text.clone(), prog[close + 1].span.clone()));
+                    for (i, name) in names.iter().enumerate() {
+                        let name_span = prog[idx + 1 + i].span.clone();
+                        for command in [Command::Pushs(name.clone()), Command::Define(Visibility::Public, 0), Command::Return, Command::EndDefine] {
+                            commands.push(command);
+                            source.push(SourceReference::Invisible(name_span.clone()));
+                        }
+                    }
+                    commands.push(Command::Parameters(names.clone()));
+                    source.push(SourceReference::Visible(prog[idx].text.clone(), span.clone()));
+                    for name in names.iter().rev() {
+                        commands.push(Command::ArrowPut);
+                        source.push(SourceReference::Invisible(span.clone()));
+                        commands.push(Command::NamedReference(format!("@::{}", name), 0));
+                        source.push(SourceReference::Invisible(span.clone()));
                     }
-                    stuff[stuff.len() - 1].clone()
+
+                    idx = close + 1;
+                    Command::Nop
+                },
+                s if s.starts_with('(') => {
+                    let parsed = arithparser::parse(&prog, &mut idx)
+                        .map_err(|(msg, span)| SyntaxError::new(msg, env.span_location(&span)))?;
+                    for (command, source_ref) in parsed {
+                        commands.push(command);
+                        source.push(source_ref);
+                    }
+                    Command::Nop
                 }
                 s =>
                     Command::Pushs(String::from(s))
@@ -965,26 +2212,29 @@ fn lexer(program: String) -> Environment {
             Command::Nop => {},
              n => {
                 commands.push(n);
-                source.push(SourceReference::Visible(prog[idx].to_string()));
+                source.push(SourceReference::Visible(prog[idx].text.clone(), span));
             }
         }
         idx += 1;
     }
 
-    Environment::new(commands, source)
+    Ok(Environment::empty(commands, source))
 }
 
 
-pub fn run_string(mut env: &mut Environment, input: &str) -> Result<(), RuntimeError> {
-    let mut result = lexer(input.to_string());
+pub fn run_string(env: &mut Environment, input: &str) -> Result<(), Error> {
+    run_source(env, "<input>", input)
+}
 
-    env.program.append(&mut result.program);
-    env.source.append(&mut result.source);
-    let test = parser(&mut env);
+/// like `run_string`, `name` is used to refer to the code in error messages
+pub fn run_source(env: &mut Environment, name: &str, input: &str) -> Result<(), Error> {
+    load(env, name, input, env.program.len())?;
 
+    env.unwind(0);
+    env.list_starts.clear();
     let res = run(env);
     env.idx = env.program.len();
 
-    res
+    Ok(res?)
 }
 
@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+/// the files of `lib/`, built into the interpreter as its standard library
+const LIBRARY: &[(&str, &str)] = &[
+    ("ascii.hr", include_str!("../lib/ascii.hr")),
+    ("generator.hr", include_str!("../lib/generator.hr")),
+    ("linkedlist.hr", include_str!("../lib/linkedlist.hr")),
+    ("lists.hr", include_str!("../lib/lists.hr")),
+    ("maps.hr", include_str!("../lib/maps.hr")),
+    ("memory.hr", include_str!("../lib/memory.hr")),
+    ("ringbuffer.hr", include_str!("../lib/ringbuffer.hr")),
+    ("rng.hr", include_str!("../lib/rng.hr")),
+    ("std.hr", include_str!("../lib/std.hr"))
+];
+
+/// files of the standard library are named like this in error messages
+const LIBRARY_PREFIX: &str = "<lib>/";
+
+/// A file that was found for an `include` or `import`.
+pub(crate) struct Found {
+    /// the same for every way to name the file, its canonical path
+    pub(crate) key: String,
+    /// how the file is called in error messages
+    pub(crate) name: String,
+    text: Option<&'static str>
+}
+
+impl Found {
+    pub(crate) fn read(&self) -> io::Result<String> {
+        match self.text {
+            Some(text) => Ok(text.into()),
+            None => fs::read_to_string(&self.key)
+        }
+    }
+}
+
+/// The search path given by the `HORRIBLE_PATH` environment variable, empty if it isn't set.
+pub(crate) fn search_path_from_env() -> Vec<PathBuf> {
+    std::env::var_os("HORRIBLE_PATH")
+        .map(|paths| std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Find `filename` for an include written in the file `from`.
+///
+/// The directory of `from` comes first, code that isn't read from a file like `<input>` looks in the
+/// working directory. Then every directory of the search path is tried and finally the standard
+/// library. Files of the standard library include each other before anything else.
+pub(crate) fn find(filename: &str, from: &str, search_path: &[PathBuf]) -> io::Result<Found> {
+    if from.starts_with(LIBRARY_PREFIX) {
+        if let Some(found) = embedded(filename) {
+            return Ok(found);
+        }
+    }
+
+    let base = match Path::new(from).parent() {
+        Some(dir) if !from.starts_with('<') => dir,
+        _ => Path::new("")
+    };
+    let candidates = std::iter::once(base).chain(search_path.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(filename));
+
+    for candidate in candidates {
+        if let Ok(canonical) = fs::canonicalize(&candidate) {
+            if canonical.is_file() {
+                return Ok(Found {
+                    key: canonical.to_string_lossy().into_owned(),
+                    name: candidate.to_string_lossy().into_owned(),
+                    text: None
+                });
+            }
+        }
+    }
+
+    let place = if base.as_os_str().is_empty() { String::from("in the working directory") } else { format!("next to `{}`", from) };
+    embedded(filename).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+        format!("`{}` is neither {}, in the search path nor in the standard library", filename, place)))
+}
+
+fn embedded(filename: &str) -> Option<Found> {
+    LIBRARY.iter()
+        .find(|(name, _)| *name == filename)
+        .map(|(name, text)| Found {
+            key: format!("{}{}", LIBRARY_PREFIX, name),
+            name: format!("{}{}", LIBRARY_PREFIX, name),
+            text: Some(text)
+        })
+}
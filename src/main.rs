@@ -1,66 +1,331 @@
+use std::cell::RefCell;
 use std::env;
 use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::time;
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 
-fn main() {
-    let mut env = horrible::Environment::new(vec![], vec![]);
-    let args: Vec<String> = env::args().collect();
+/// `horrible test [--format plain|tap|junit] [paths]`, returns the exit status
+fn test(args: &[String]) -> i32 {
+    let mut format = horrible::ReportFormat::Plain;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
 
-    let arg_string = args.iter().map(|i| { format!("\"{}\"", i) }).collect::<Vec<String>>().join(" ");
-    horrible::run_string(&mut env, &format!("std.hr include | {}", arg_string))
-        .expect("unable to parse arguments");
+    while let Some(arg) = args.next() {
+        if arg == "--format" {
+            format = match args.next().map(String::as_str) {
+                Some("plain") => horrible::ReportFormat::Plain,
+                Some("tap") => horrible::ReportFormat::Tap,
+                Some("junit") => horrible::ReportFormat::Junit,
+                other => {
+                    eprintln!("unknown report format: {}, use plain, tap or junit", other.unwrap_or_default());
+                    return 2;
+                }
+            };
+        } else {
+            paths.push(PathBuf::from(arg));
+        }
+    }
+    if paths.is_empty() {
+        paths.push(PathBuf::from("test"));
+    }
 
-    let mut rl = Editor::<()>::new();
+    let files = match horrible::discover_tests(&paths) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+    let results: Vec<horrible::TestResult> = files.iter()
+        .flat_map(|file| horrible::run_test_file(file))
+        .collect();
 
-    if args.len() > 1 {
-        let mut filename = &args[1];
+    println!("{}", horrible::report(&results, format));
+    if results.iter().all(|r| r.passed()) { 0 } else { 1 }
+}
 
-        if filename == "-i" {
-            filename = &args[2];
-        }
+/// code that runs before every script
+const PRELUDE: &str = "std.hr include";
 
-        let contents = fs::read_to_string(filename)
-            .expect("Something went wrong reading the file");
+/// `horrible compile [-I <dir>] <file> [-o <output>]`, returns the exit status
+fn compile(args: &[String]) -> i32 {
+    const COMPILE_USAGE: &str = "usage: horrible compile [-I <dir>] <file> [-o <output>]";
 
-        match horrible::run_string(&mut env, &contents) {
-            Ok(_env) => {},
-            Err(err) => println!("{}", err),
+    let mut include_dirs = Vec::new();
+    let mut input = None;
+    let mut output = None;
+    let mut rest = args;
+    while let Some((arg, tail)) = rest.split_first() {
+        rest = match (arg.as_str(), tail) {
+            ("-I", [dir, tail @ ..]) => {
+                include_dirs.push(PathBuf::from(dir));
+                tail
+            },
+            ("-o", [file, tail @ ..]) if output.is_none() => {
+                output = Some(PathBuf::from(file));
+                tail
+            },
+            (file, _) if input.is_none() && !file.starts_with('-') => {
+                input = Some(arg);
+                tail
+            },
+            _ => {
+                eprintln!("{}", COMPILE_USAGE);
+                return 2;
+            }
         };
     }
-    else {
-        loop {
-            let readline = rl.readline(
-                if env.execute  {
-                    ">> "
+    let Some(input) = input else {
+        eprintln!("{}", COMPILE_USAGE);
+        return 2;
+    };
+    let output = output.unwrap_or_else(|| PathBuf::from(input).with_extension("hrc"));
+
+    let contents = match fs::read_to_string(input) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("unable to read {}: {}", input, err);
+            return 2;
+        }
+    };
+    let mut env = horrible::Environment::new(vec![], vec![]);
+    env.enable_files();
+    // the same search path as running the file, so includes are resolved the same way
+    include_dirs.extend(env.search_path().iter().cloned());
+    env.set_search_path(include_dirs);
+    let code = match horrible::Bytecode::compile(&mut env, PRELUDE, input, &contents) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+
+    if let Err(err) = fs::write(&output, code.to_bytes()) {
+        eprintln!("unable to write {}: {}", output.display(), err);
+        return 2;
+    }
+    0
+}
+
+/// the usage line printed for unknown or incomplete options
+const USAGE: &str = "usage: horrible [options] [-e <code> | <file> | -] [--] [arguments]";
+
+/// the exit status for a script that failed with `err`
+fn status(err: &horrible::Error) -> i32 {
+    match err {
+        horrible::Error::Syntax(_) => 2,
+        horrible::Error::Runtime(_) => 1
+    }
+}
+
+/// `process::exit` skips the buffer of stdout, so whatever the script printed last is flushed first
+fn exit(code: i32) -> ! {
+    io::stdout().flush().ok();
+    process::exit(code)
+}
+
+/// run lines typed by the user until the input ends or the script calls `exit`, returns the exit status
+fn repl(env: &mut horrible::Environment) -> i32 {
+    let mut rl = Editor::<()>::new();
+
+    loop {
+        let readline = rl.readline(
+            if env.execute  {
+                ">> "
+            }
+            else {
+                ".. "
+            }
+        );
+        match readline {
+            Ok(line) => {
+                rl.add_history_entry(line.as_str());
+                if let Err(err) = horrible::run_string(env, &line) {
+                    eprintln!("{}", err);
                 }
-                else {
-                    ".. "
+                if let Some(code) = env.exit_code() {
+                    return code;
                 }
-            );
-            match readline {
-                Ok(line) => {
-                    rl.add_history_entry(line.as_str());
-                    match horrible::run_string(&mut env, &line) {
-                        Ok(_env) => {},
-                        Err(err) => println!("{}", err),
-                    };
-                },
-                Err(ReadlineError::Interrupted) => {
-                    println!("CTRL-C");
-                    break
-                },
-                Err(ReadlineError::Eof) => {
-                    println!("CTRL-D");
-                    break
+            },
+            Err(ReadlineError::Interrupted) => {
+                println!("CTRL-C");
+                break
+            },
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                break
+            },
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+                return 1
+            }
+        }
+    }
+    0
+}
+
+/// the program named on the command line, `-` reads it from stdin
+fn read_program(filename: &str) -> Vec<u8> {
+    let contents = if filename == "-" {
+        let mut contents = Vec::new();
+        io::stdin().read_to_end(&mut contents).map(|_| contents)
+    } else {
+        fs::read(filename)
+    };
+
+    contents.unwrap_or_else(|err| {
+        eprintln!("unable to read {}: {}", filename, err);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("test") => process::exit(test(&args[2..])),
+        Some("compile") => process::exit(compile(&args[2..])),
+        _ => {}
+    }
+
+    let mut env = horrible::Environment::new(vec![], vec![]);
+    env.enable_files();
+
+    let mut interactive = false;
+    let mut prelude = true;
+    let mut code = None;
+    let mut debug = false;
+    let mut limits = horrible::Limits::default();
+    let mut include_dirs = Vec::new();
+    let mut first = 1;
+    while let Some(flag) = args.get(first) {
+        let value = args.get(first + 1).map(String::as_str);
+        let parsed = match flag.as_str() {
+            "-i" => {
+                interactive = true;
+                Ok(())
+            },
+            "-e" => value.map(|c| code = Some(c.to_string())).ok_or(()),
+            "-I" => value.map(|dir| include_dirs.push(PathBuf::from(dir))).ok_or(()),
+            "--no-std" => {
+                prelude = false;
+                Ok(())
+            },
+            "--debug" => {
+                debug = true;
+                Ok(())
+            },
+            "--max-instructions" => value.and_then(|v| v.parse().ok()).map(|n| limits.instructions = Some(n)).ok_or(()),
+            "--timeout" => value.and_then(|v| v.parse().ok()).and_then(|s| time::Duration::try_from_secs_f64(s).ok())
+                .map(|t| limits.time = Some(t)).ok_or(()),
+            "--max-stack" => value.and_then(|v| v.parse().ok()).map(|n| limits.stack_size = Some(n)).ok_or(()),
+            "--max-call-depth" => value.and_then(|v| v.parse().ok()).map(|n| limits.call_depth = Some(n)).ok_or(()),
+            "--max-program" => value.and_then(|v| v.parse().ok()).map(|n| limits.program_size = Some(n)).ok_or(()),
+            "--max-heap" => value.and_then(|v| v.parse().ok()).map(|n| limits.heap_size = Some(n)).ok_or(()),
+            "--" => {
+                first += 1;
+                break;
+            },
+            "-" => break,
+            _ if flag.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", flag, USAGE);
+                process::exit(2);
+            },
+            _ => break
+        };
+        if parsed.is_err() {
+            eprintln!("invalid value for {}: `{}`\n{}", flag, value.unwrap_or_default(), USAGE);
+            process::exit(2);
+        }
+        first += if flag.starts_with("--max") || flag == "--timeout" || flag == "-e" || flag == "-I" { 2 } else { 1 };
+    }
+
+    // directories given with `-I` are searched before the ones in `HORRIBLE_PATH`
+    include_dirs.extend(env.search_path().iter().cloned());
+    env.set_search_path(include_dirs);
+
+    // without `-e` the first argument that is left names the script, everything after it is for the script
+    let (name, program, rest) = match (code, args.get(first)) {
+        (Some(code), _) => (String::from("<-e>"), Some(code.into_bytes()), &args[first..]),
+        (None, Some(filename)) => {
+            let name = if filename == "-" { String::from("<stdin>") } else { filename.clone() };
+            (name, Some(read_program(filename)), &args[first + 1..])
+        },
+        (None, None) => (String::from("<input>"), None, &args[first..])
+    };
+    let script_args = match rest {
+        [separator, rest @ ..] if separator == "--" => rest,
+        rest => rest
+    };
+
+    // the debugger is attached after the prelude, so it stops at the first instruction of the script
+    let debugger = if debug { Some(Rc::new(RefCell::new(horrible::Debugger::new()))) } else { None };
+
+    if let Some(contents) = &program {
+        if horrible::Bytecode::is_bytecode(contents) {
+            let result = horrible::Bytecode::from_bytes(contents)
+                .map(|code| code.run(&mut env, |env| {
+                    env.set_args(script_args.to_vec());
+                    env.set_debugger(debugger.clone());
+                    env.set_limits(limits.clone());
+                }));
+            let mut code = match result {
+                Ok(Ok(())) => 0,
+                Ok(Err(err)) => {
+                    eprintln!("{}", err);
+                    status(&err)
                 },
                 Err(err) => {
-                    println!("Error: {:?}", err);
-                    break
+                    eprintln!("{}", err);
+                    2
                 }
+            };
+            if let Some(code) = env.exit_code() {
+                exit(code);
+            }
+            // a failed script still fails after the REPL ends
+            if interactive {
+                code = code.max(repl(&mut env));
+            }
+            exit(code);
+        }
+    }
+
+    if prelude {
+        horrible::run_string(&mut env, PRELUDE).expect("unable to load the standard library");
+    }
+    env.set_args(script_args.to_vec());
+    env.set_debugger(debugger);
+    env.set_limits(limits);
+
+    let mut code = 0;
+    if let Some(contents) = program {
+        let contents = String::from_utf8(contents).unwrap_or_else(|_| {
+            eprintln!("unable to read {}: the program is not valid UTF-8", name);
+            process::exit(2);
+        });
+
+        code = match horrible::run_source(&mut env, &name, &contents) {
+            Ok(_env) => 0,
+            Err(err) => {
+                eprintln!("{}", err);
+                status(&err)
             }
+        };
+        if let Some(code) = env.exit_code() {
+            exit(code);
+        }
+        if !interactive {
+            exit(code);
         }
     }
+
+    exit(code.max(repl(&mut env)));
 }
@@ -0,0 +1,323 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{run_source, run_string, tokenizer, CaptureIo, Command, Environment, Error, Limits};
+
+
+/// code that runs before every test, the interpreter does the same before a script
+const PRELUDE: &str = "std.hr include";
+
+/// a test that runs longer than this is considered hanging
+const TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// prefix that marks a definition as a test case of its own
+const TEST_PREFIX: &str = "test-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Plain,
+    Tap,
+    Junit
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    Failed(String)
+}
+
+/// The result of a single test case, either a whole file or one `test-` definition in it.
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub file: PathBuf,
+    pub outcome: TestOutcome,
+    pub duration: Duration
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+    }
+}
+
+/// Collect the test files in `paths`.
+///
+/// Files that are named directly are always tests. Directories are searched recursively for `.hr`
+/// files that use `assert` or `assert-eq`, contain a top level `test-` definition or have an expected
+/// output file (`name.out` next to `name.hr`).
+pub fn discover_tests(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            discover_directory(path, &mut tests)?;
+        } else if path.exists() {
+            tests.push(path.clone());
+        } else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no such file or directory: {}", path.display())));
+        }
+    }
+    Ok(tests)
+}
+
+fn discover_directory(dir: &Path, tests: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            discover_directory(&path, tests)?;
+        } else if is_test(&path) {
+            tests.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_test(path: &Path) -> bool {
+    if path.extension().is_none_or(|ext| ext != "hr") {
+        return false;
+    }
+    if expected_output_path(path).exists() {
+        return true;
+    }
+
+    let tokens = match fs::read_to_string(path).map(|text| tokenizer::tokenize(&text, 0)) {
+        Ok(Ok(tokens)) => tokens,
+        _ => return false
+    };
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text.as_str() {
+            "assert" | "assert-eq" => return true,
+            "is" | "priv" | "{" | "lambda" | "try" => depth += 1,
+            "in" | "}" => depth -= 1,
+            name if depth == 0 && name.starts_with(TEST_PREFIX)
+                && tokens.get(i + 1).is_some_and(|next| next.text == "is" || next.text == "{") => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn expected_output_path(path: &Path) -> PathBuf {
+    path.with_extension("out")
+}
+
+/// Run one test file.
+///
+/// The file itself is the first test case: it passes if it runs without an error and, when there
+/// is an expected output file, prints exactly that. Every `test-` definition in the file is a
+/// further test case, run in a fresh environment after loading the file. A test that calls `exit`
+/// with a status other than 0 fails, and no further tests of the file run after an `exit`.
+pub fn run_test_file(path: &Path) -> Vec<TestResult> {
+    let name = path.display().to_string();
+    let start = Instant::now();
+
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return vec![TestResult {
+            name: name.clone(),
+            file: path.into(),
+            outcome: TestOutcome::Failed(format!("unable to read {}: {}", name, err)),
+            duration: start.elapsed()
+        }]
+    };
+
+    let loaded = load(path, &text);
+    let outcome = match &loaded {
+        Ok((env, io)) => exit_outcome(env.exit_code()).unwrap_or_else(|| check_output(path, io.borrow().output())),
+        Err(err) => TestOutcome::Failed(err.to_string())
+    };
+    let mut results = vec![TestResult { name: name.clone(), file: path.into(), outcome, duration: start.elapsed() }];
+
+    // a file that exited didn't finish loading its tests
+    if let Some((env, _)) = loaded.as_ref().ok().filter(|(env, _)| env.exit_code().is_none()) {
+        for definition in test_definitions(env) {
+            let start = Instant::now();
+            let run = load(path, &text).and_then(|(mut env, _)| {
+                run_string(&mut env, &format!("@{}!", definition))?;
+                Ok(env.exit_code())
+            });
+            let outcome = match &run {
+                Ok(code) => exit_outcome(*code).unwrap_or(TestOutcome::Passed),
+                Err(err) => TestOutcome::Failed(err.to_string())
+            };
+            results.push(TestResult {
+                name: format!("{}::{}", name, definition),
+                file: path.into(),
+                outcome,
+                duration: start.elapsed()
+            });
+            if matches!(run, Ok(Some(_))) {
+                break;
+            }
+        }
+    }
+    results
+}
+
+/// a test that called `exit` with a status other than 0 failed
+fn exit_outcome(code: Option<i32>) -> Option<TestOutcome> {
+    code.filter(|&code| code != 0).map(|code| TestOutcome::Failed(format!("the test called exit with status {}", code)))
+}
+
+/// a fresh environment with the prelude and the test file loaded, output goes to a buffer
+fn load(path: &Path, text: &str) -> Result<(Environment, Rc<RefCell<CaptureIo>>), Error> {
+    let io = Rc::new(RefCell::new(CaptureIo::new()));
+    let mut env = Environment::new(vec![], vec![]);
+    env.enable_files();
+    env.set_io(io.clone());
+
+    run_string(&mut env, PRELUDE)?;
+    io.borrow_mut().take_output();
+    env.set_limits(Limits { time: Some(TIME_LIMIT), ..Limits::default() });
+    run_source(&mut env, &path.display().to_string(), text)?;
+
+    Ok((env, io))
+}
+
+/// names of the top level `test-` definitions, in the order they appear in the program
+fn test_definitions(env: &Environment) -> Vec<String> {
+    let mut top_level = BTreeMap::new();
+    let mut depth = 0;
+
+    for (position, command) in env.program.iter().enumerate() {
+        match command {
+            Command::Define(_, _) => {
+                if depth == 0 {
+                    top_level.insert(position, None);
+                }
+                depth += 1;
+            },
+            Command::Lambda(_) => depth += 1,
+            Command::EndDefine => depth -= 1,
+            _ => {}
+        }
+    }
+
+    for (name, position) in &env.definitions {
+        if let Some(entry) = top_level.get_mut(position) {
+            if !name.contains("::") && name.starts_with(TEST_PREFIX) {
+                *entry = Some(name.clone());
+            }
+        }
+    }
+    top_level.into_values().flatten().collect()
+}
+
+fn check_output(path: &Path, output: &str) -> TestOutcome {
+    let expected_path = expected_output_path(path);
+    if !expected_path.exists() {
+        return TestOutcome::Passed;
+    }
+
+    match fs::read_to_string(&expected_path) {
+        Ok(expected) if expected == output => TestOutcome::Passed,
+        Ok(expected) => TestOutcome::Failed(output_difference(&expected_path, &expected, output)),
+        Err(err) => TestOutcome::Failed(format!("unable to read {}: {}", expected_path.display(), err))
+    }
+}
+
+fn output_difference(expected_path: &Path, expected: &str, output: &str) -> String {
+    let mut expected_lines = expected.lines();
+    let mut output_lines = output.lines();
+    let mut line = 1;
+
+    loop {
+        match (expected_lines.next(), output_lines.next()) {
+            (Some(e), Some(o)) if e == o => line += 1,
+            (None, None) => return format!("output differs from {} in the final newline", expected_path.display()),
+            (e, o) => return format!(
+                "output differs from {} at line {}\n  expected: {}\n  got:      {}",
+                expected_path.display(), line,
+                e.map_or(String::from("<end of output>"), |e| format!("{:?}", e)),
+                o.map_or(String::from("<end of output>"), |o| format!("{:?}", o))
+            )
+        }
+    }
+}
+
+/// Format the results for the console or for CI tools.
+pub fn report(results: &[TestResult], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Plain => report_plain(results),
+        ReportFormat::Tap => report_tap(results),
+        ReportFormat::Junit => report_junit(results)
+    }
+}
+
+fn report_plain(results: &[TestResult]) -> String {
+    let mut out = String::new();
+    let failed = results.iter().filter(|r| !r.passed()).count();
+
+    for result in results {
+        match &result.outcome {
+            TestOutcome::Passed => writeln!(out, "PASS {}", result.name).unwrap(),
+            TestOutcome::Failed(msg) => {
+                writeln!(out, "FAIL {}", result.name).unwrap();
+                for line in msg.lines() {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+            }
+        }
+    }
+    write!(out, "\n{} passed, {} failed", results.len() - failed, failed).unwrap();
+    out
+}
+
+fn report_tap(results: &[TestResult]) -> String {
+    let mut out = format!("TAP version 13\n1..{}", results.len());
+
+    for (i, result) in results.iter().enumerate() {
+        match &result.outcome {
+            TestOutcome::Passed => write!(out, "\nok {} - {}", i + 1, result.name).unwrap(),
+            TestOutcome::Failed(msg) => {
+                write!(out, "\nnot ok {} - {}\n  ---\n  message: |", i + 1, result.name).unwrap();
+                for line in msg.lines() {
+                    write!(out, "\n    {}", line).unwrap();
+                }
+                write!(out, "\n  ...").unwrap();
+            }
+        }
+    }
+    out
+}
+
+fn report_junit(results: &[TestResult]) -> String {
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    let time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+
+    writeln!(out, "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">", results.len(), failed, time).unwrap();
+    writeln!(out, "  <testsuite name=\"horrible\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">", results.len(), failed, time).unwrap();
+    for result in results {
+        write!(out, "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            xml_escape(&result.name), xml_escape(&result.file.display().to_string()), result.duration.as_secs_f64()).unwrap();
+        match &result.outcome {
+            TestOutcome::Passed => writeln!(out, "/>").unwrap(),
+            TestOutcome::Failed(msg) => {
+                let summary = msg.lines().next().unwrap_or_default();
+                writeln!(out, ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>", xml_escape(summary), xml_escape(msg)).unwrap();
+            }
+        }
+    }
+    write!(out, "  </testsuite>\n</testsuites>").unwrap();
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
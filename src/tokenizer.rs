@@ -0,0 +1,121 @@
+use crate::Span;
+
+
+/// A piece of source text together with the position it was found at.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub text: String,
+    pub span: Span
+}
+
+struct Cursor<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    file: usize,
+    line: usize,
+    column: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn span(&self, length: usize) -> Span {
+        Span { file: self.file, line: self.line, column: self.column, length }
+    }
+
+    fn skip_line(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.next();
+        }
+    }
+}
+
+fn is_bracket(c: char) -> bool {
+    matches!(c, '(' | ')' | '[' | ']')
+}
+
+fn ends_token(c: Option<char>) -> bool {
+    match c {
+        None => true,
+        Some(c) => c.is_whitespace() || is_bracket(c)
+    }
+}
+
+/// Split source code into whitespace separated tokens.
+///
+/// Parentheses and square brackets are always tokens of their own, `//` starts a comment that reaches to the end of
+/// the line and a token starting with `"` extends to the next unescaped `"` that is followed by
+/// whitespace, so strings keep their inner spacing. A string that is never closed is reported with
+/// the span of its opening quote. A first line starting with `#!` is skipped, so scripts can be
+/// made executable.
+pub fn tokenize(text: &str, file: usize) -> Result<Vec<Token>, Span> {
+    let mut cursor = Cursor { chars: text.chars().peekable(), file, line: 1, column: 1 };
+    let mut tokens = Vec::new();
+
+    if text.starts_with("#!") {
+        cursor.skip_line();
+    }
+
+    while let Some(c) = cursor.peek() {
+        if c.is_whitespace() {
+            cursor.next();
+            continue;
+        }
+
+        let mut span = cursor.span(0);
+        let mut token = String::new();
+
+        if is_bracket(c) {
+            token.push(c);
+            cursor.next();
+        } else if c == '"' {
+            token.push(c);
+            cursor.next();
+
+            let mut closed = false;
+            while let Some(c) = cursor.next() {
+                let escaped = token.ends_with('\\');
+                token.push(c);
+                if c == '"' && !escaped && ends_token(cursor.peek()) {
+                    closed = true;
+                    break;
+                }
+            }
+
+            if !closed {
+                span.length = 1;
+                return Err(span);
+            }
+        } else {
+            while !ends_token(cursor.peek()) {
+                let c = cursor.next().unwrap();
+                if c == '/' && cursor.peek() == Some('/') {
+                    cursor.skip_line();
+                    break;
+                }
+                token.push(c);
+            }
+
+            if token.is_empty() {
+                continue;
+            }
+        }
+
+        span.length = token.chars().count();
+        tokens.push(Token { text: token, span });
+    }
+
+    Ok(tokens)
+}
@@ -0,0 +1,31 @@
+//! Compiled files are read from disk, so damaged ones must be rejected instead of crashing.
+
+use horrible::{Bytecode, BytecodeError, Environment};
+
+/// the tag that `Bytecode::to_bytes` writes in front of the skip of a `lambda`
+const TAG_LAMBDA: u8 = 205;
+
+/// FNV-1a like the checksum in the header, so the damage isn't caught by it
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+#[test]
+fn jumps_outside_of_the_program_are_rejected() {
+    let mut env = Environment::new(vec![], vec![]);
+    let mut bytes = Bytecode::compile(&mut env, "", "<test>", "lambda 1 in drop").unwrap().to_bytes();
+
+    let skip = [[TAG_LAMBDA].as_slice(), &2u64.to_le_bytes()].concat();
+    let at = bytes.windows(skip.len()).position(|window| window == skip).expect("the lambda is in the bytecode");
+    bytes[at + 1..at + 9].copy_from_slice(&u64::MAX.to_le_bytes());
+
+    // magic, format version, length and text of the interpreter version, then the checksum
+    let header = 4 + 4 + 8 + env!("CARGO_PKG_VERSION").len();
+    let sum = checksum(&bytes[header + 8..]);
+    bytes[header..header + 8].copy_from_slice(&sum.to_le_bytes());
+
+    match Bytecode::from_bytes(&bytes) {
+        Err(BytecodeError::Corrupt(msg)) => assert!(msg.contains("outside of the program"), "{}", msg),
+        other => panic!("expected corrupted bytecode, got {:?}", other.map(|_| ()))
+    }
+}
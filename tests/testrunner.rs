@@ -0,0 +1,46 @@
+//! Test files that have to fail or must be found in a certain way, so they can't live in `test/`.
+
+use std::path::PathBuf;
+
+use horrible::{discover_tests, run_test_file, TestOutcome, TestResult};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+fn run_fixture(name: &str) -> Vec<TestResult> {
+    run_test_file(&fixture(name))
+}
+
+fn assert_fails_with(result: &TestResult, message: &str) {
+    match &result.outcome {
+        TestOutcome::Failed(msg) => assert!(msg.contains(message), "`{}` doesn't mention `{}`", msg, message),
+        TestOutcome::Passed => panic!("expected {} to fail with `{}`", result.name, message)
+    }
+}
+
+#[test]
+fn huge_alloc_exceeds_the_limit() {
+    assert_fails_with(&run_fixture("hugealloc.hr")[0], "limit-exceeded");
+}
+
+#[test]
+fn exit_with_a_status_fails_and_stops_the_file() {
+    let results = run_fixture("exittest.hr");
+    assert_eq!(results.len(), 3, "{:?}", results);
+    assert!(results[1].passed());
+    assert_fails_with(&results[2], "status 3");
+}
+
+#[test]
+fn exit_while_loading_fails_the_file() {
+    let results = run_fixture("exitfile.hr");
+    assert_eq!(results.len(), 1, "{:?}", results);
+    assert_fails_with(&results[0], "status 1");
+}
+
+#[test]
+fn tests_after_a_top_level_try_are_found() {
+    let tests = discover_tests(&[fixture("discovery")]).unwrap();
+    assert_eq!(tests, vec![fixture("discovery/aftertry.hr")]);
+}